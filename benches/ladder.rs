@@ -0,0 +1,132 @@
+// MapLadder against TickLadder on a stream of market changes. The frames
+// come from benches/data/mcm.jsonl, one match odds market in the stream's
+// wire format, one frame per line. Set BFG_MCM_REPLAY to a file of your own
+// recorded frames to replay those instead. Generated frames are only used
+// when neither can be read.
+
+use bfg::{
+    ladder::{Ladder, MapLadder, Side, TickLadder},
+    price::Price,
+    stream::model::{MarketChangeMessage, ResponseMessage},
+};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::collections::HashMap;
+
+const RECORDED: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/data/mcm.jsonl");
+const FRAMES: usize = 10_000;
+const RUNNERS: i64 = 10;
+
+fn recorded(path: &str) -> Option<Vec<MarketChangeMessage>> {
+    let frames: Vec<MarketChangeMessage> = std::fs::read_to_string(path)
+        .ok()?
+        .lines()
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(ResponseMessage::Mcm(mcm)) => Some(mcm),
+            _ => None,
+        })
+        .collect();
+    (!frames.is_empty()).then_some(frames)
+}
+
+// Prices wandering around a mid a few ticks wide, the way a busy market
+// looks between images
+fn generated() -> Vec<MarketChangeMessage> {
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut random = move |below: u64| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed % below
+    };
+    let mut mids: Vec<Price> = (0..RUNNERS)
+        .map(|runner| Price::from_tick(80 + runner as usize * 15).unwrap())
+        .collect();
+    (0..FRAMES)
+        .map(|pt| {
+            let runners: Vec<String> = (0..1 + random(3))
+                .map(|_| {
+                    let runner = random(RUNNERS as u64) as usize;
+                    mids[runner] = mids[runner].offset_clamped(random(3) as isize - 1);
+                    let mid = mids[runner];
+                    let mut levels = |back: bool| {
+                        (0..1 + random(4))
+                            .map(|_| {
+                                let away = 1 + random(5) as usize;
+                                let price = if back {
+                                    mid.ticks_down(away)
+                                } else {
+                                    mid.ticks_up(away)
+                                };
+                                // A third of the updates take a level away
+                                let size = random(300).saturating_sub(100);
+                                format!("[{},{}]", price.unwrap_or(mid), size)
+                            })
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    };
+                    format!(
+                        r#"{{"id":{},"atb":[{}],"atl":[{}]}}"#,
+                        runner,
+                        levels(true),
+                        levels(false)
+                    )
+                })
+                .collect();
+            let frame = format!(
+                r#"{{"pt":{},"mc":[{{"id":"1.1","rc":[{}]}}]}}"#,
+                pt,
+                runners.join(",")
+            );
+            serde_json::from_str(&frame).unwrap()
+        })
+        .collect()
+}
+
+// Every runner change applied to its own ladder, reading the top of the
+// book after each one like the ladder view would
+fn replay<L: Ladder + Default>(frames: &[MarketChangeMessage], read: bool) -> usize {
+    let mut ladders: HashMap<i64, L> = HashMap::new();
+    let mut levels = 0;
+    for mcm in frames {
+        for change in mcm.mc.iter().flatten() {
+            for runner in change.rc.iter().flatten() {
+                let ladder = ladders.entry(runner.id).or_default();
+                ladder.apply(runner, mcm.pt);
+                if read {
+                    levels += ladder.top(Side::Back, 3).len() + ladder.top(Side::Lay, 3).len();
+                    black_box(ladder.spread());
+                }
+            }
+        }
+    }
+    levels
+}
+
+fn ladders(c: &mut Criterion) {
+    let path = std::env::var("BFG_MCM_REPLAY").unwrap_or_else(|_| String::from(RECORDED));
+    let frames = recorded(&path).unwrap_or_else(|| {
+        eprintln!("No frames in {}, generating them", path);
+        generated()
+    });
+
+    let mut group = c.benchmark_group("update");
+    group.bench_function("map", |b| {
+        b.iter(|| replay::<MapLadder>(black_box(&frames), false))
+    });
+    group.bench_function("tick", |b| {
+        b.iter(|| replay::<TickLadder>(black_box(&frames), false))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("update and read");
+    group.bench_function("map", |b| {
+        b.iter(|| replay::<MapLadder>(black_box(&frames), true))
+    });
+    group.bench_function("tick", |b| {
+        b.iter(|| replay::<TickLadder>(black_box(&frames), true))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ladders);
+criterion_main!(benches);
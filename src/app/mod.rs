@@ -1,2 +1,3 @@
 pub use super::*;
 pub mod model;
+pub mod port;
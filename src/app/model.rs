@@ -1,24 +1,61 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
 
-use crate::components::{LadderComponent, MarketsComponent, PhantomComponent, StatusComponent};
+use crate::{
+    components::{
+        LadderComponent, MarketsComponent, PhantomComponent, RaceComponent, StatusComponent,
+        LEVELS, SUSPENDED,
+    },
+    odds::OddsFormat,
+    stream::{
+        model::{ErrorCode, MarketStatus, ResponseMessage, RunnerStatus, StatusCode},
+        CacheSnapshot, Interest, LatencySnapshot, MarketCache, MarketEvent, OrderCache,
+        OrderNotification, PriceLadder, RaceCache, SnapshotFormat, StreamEvent, StreamSession,
+        SubscriptionManager,
+    },
+};
 
-use super::{Id, Msg};
+use super::{port::StreamPort, Id, Msg, UserEvent};
 use tuirealm::{
     event::{Key, KeyEvent, KeyModifiers},
+    props::{Alignment, PropPayload, PropValue},
     terminal::TerminalBridge,
     tui::layout::{Constraint, Direction, Layout},
-    Application, EventListenerCfg, NoUserEvent, Sub, Update,
+    Application, AttrValue, Attribute, EventListenerCfg, Sub, Update,
 };
 
+// Connections report their latency every second while frames arrive
+const LATENCY_KEPT: Duration = Duration::from_secs(5);
+
 pub struct Model {
     /// Application
-    pub app: Application<Id, Msg, NoUserEvent>,
+    pub app: Application<Id, Msg, UserEvent>,
     /// Indicates that the application must quit
     pub quit: bool,
     /// Tells whether to redraw interface
     pub redraw: bool,
     /// Used to draw to terminal
     pub terminal: TerminalBridge,
+    /// Last thing the stream told us
+    status: String,
+    /// How far behind each connection is and when it said so, the worst one
+    /// is shown next to the status
+    latency: HashMap<usize, (Instant, LatencySnapshot)>,
+    /// Every market streamed
+    markets: MarketCache,
+    /// Our orders
+    orders: OrderCache,
+    /// Tracking data of the races still running
+    races: RaceCache,
+    /// Market subscriptions for everything on screen or with orders in it
+    subscriptions: SubscriptionManager<StreamSession>,
+    /// The last sync failed, it is tried again on every tick until it goes through
+    sync_failed: bool,
+    /// The market shown in the ladder
+    ladder_market: Option<String>,
 }
 
 impl Model {
@@ -37,20 +74,151 @@ impl Model {
                     .constraints(vec![Constraint::Percentage(99), Constraint::Length(20)])
                     .split(outer_layout[0]);
 
+                // The race sits under the markets, next to the ladder
+                let side_layout = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints(vec![Constraint::Percentage(50), Constraint::Percentage(50)])
+                    .split(inner_layout[1]);
+
                 self.app.view(&Id::Ladder, f, inner_layout[0]);
-                self.app.view(&Id::Markets, f, inner_layout[1]);
+                self.app.view(&Id::Markets, f, side_layout[0]);
+                self.app.view(&Id::Race, f, side_layout[1]);
                 self.app.view(&Id::Status, f, outer_layout[1]);
             })
             .is_ok());
     }
 
-    fn init_app() -> Application<Id, Msg, NoUserEvent> {
+    /// Starts from the caches of an earlier run, the stream resumes from their clocks.
+    /// Markets we have orders in are subscribed through `subscriptions` right away.
+    pub fn new(
+        stream_events: Receiver<StreamEvent>,
+        snapshot: CacheSnapshot,
+        subscriptions: SubscriptionManager<StreamSession>,
+        odds_format: OddsFormat,
+    ) -> Self {
+        let mut model = Self {
+            app: Self::init_app(stream_events, odds_format),
+            quit: false,
+            redraw: true,
+            terminal: TerminalBridge::new().expect("Cannot initialize terminal"),
+            status: String::from("Status"),
+            latency: HashMap::new(),
+            markets: snapshot.markets,
+            orders: snapshot.orders,
+            races: RaceCache::new(),
+            subscriptions,
+            sync_failed: false,
+            ladder_market: None,
+        };
+        let open: Vec<String> = model
+            .orders
+            .markets()
+            .filter(|market| !market.closed)
+            .map(|market| market.market_id.clone())
+            .collect();
+        for market_id in open {
+            model.subscriptions.add(Interest::Orders, &market_id);
+        }
+        model.sync_subscriptions();
+        model
+    }
+
+    /// Show `market_id` in the ladder, it is subscribed for as long as it is shown
+    pub fn show_market(&mut self, market_id: &str) {
+        self.ladder_market = Some(String::from(market_id));
+        let suspended = self
+            .markets
+            .market(market_id)
+            .and_then(|market| market.definition.as_ref())
+            .is_some_and(|definition| definition.status == MarketStatus::Suspended);
+        assert!(self
+            .app
+            .attr(
+                &Id::Ladder,
+                Attribute::Custom(SUSPENDED),
+                AttrValue::Flag(suspended)
+            )
+            .is_ok());
+        self.subscriptions.replace(Interest::Ladder, [market_id]);
+        self.sync_subscriptions();
+        self.show_ladder();
+        self.show_race();
+    }
+
+    // The first runner of the shown market, highest price on top
+    fn show_ladder(&mut self) {
+        let runner = self
+            .ladder_market
+            .as_deref()
+            .and_then(|market_id| self.markets.market(market_id))
+            .and_then(|market| market.runners.values().next());
+        let mut prices: Vec<f64> = runner
+            .into_iter()
+            .flat_map(|runner| runner.atb.levels().iter().chain(runner.atl.levels()))
+            .map(|[price, _]| *price)
+            .collect();
+        prices.sort_by(|a, b| b.total_cmp(a));
+        prices.dedup();
+        let levels = prices
+            .into_iter()
+            .map(|price| {
+                let size = |ladder: &PriceLadder| ladder.size_at(price).unwrap_or(0.);
+                PropPayload::Tup3((
+                    PropValue::F64(price),
+                    PropValue::F64(runner.map_or(0., |runner| size(&runner.atb))),
+                    PropValue::F64(runner.map_or(0., |runner| size(&runner.atl))),
+                ))
+            })
+            .collect();
+        assert!(self
+            .app
+            .attr(
+                &Id::Ladder,
+                Attribute::Custom(LEVELS),
+                AttrValue::Payload(PropPayload::Linked(levels))
+            )
+            .is_ok());
+    }
+
+    // Running order of the race in the ladder, empty without tracking data
+    fn show_race(&mut self) {
+        let rows = self
+            .ladder_market
+            .as_deref()
+            .and_then(|market_id| self.races.race(market_id))
+            .map(RaceComponent::rows)
+            .unwrap_or_default();
+        assert!(self
+            .app
+            .attr(&Id::Race, Attribute::Content, AttrValue::Table(rows))
+            .is_ok());
+    }
+
+    // A failed sync is retried with the next change or tick
+    fn sync_subscriptions(&mut self) {
+        let synced = self.subscriptions.sync();
+        self.sync_failed = synced.is_err();
+        if let Err(e) = synced {
+            self.set_status(format!("Unable to subscribe to markets: {}", e));
+        }
+    }
+
+    fn init_app(
+        stream_events: Receiver<StreamEvent>,
+        odds_format: OddsFormat,
+    ) -> Application<Id, Msg, UserEvent> {
         // TODO what is events and what is different from Msg?
         // Msg are handled in update where are events handled?
         // App with event listener, what is event listener?
-        let mut app: Application<Id, Msg, NoUserEvent> = Application::init(
+        let mut app: Application<Id, Msg, UserEvent> = Application::init(
             EventListenerCfg::default()
                 .default_input_listener(Duration::from_millis(20))
+                // The port hands over one event per poll, keep the interval short
+                // so price updates don't queue up
+                .port(
+                    Box::new(StreamPort::new(stream_events)),
+                    Duration::from_millis(1),
+                )
                 .poll_timeout(Duration::from_millis(10))
                 .tick_interval(Duration::from_secs(1)),
         );
@@ -67,11 +235,19 @@ impl Model {
         assert!(app
             .mount(
                 Id::Ladder,
-                Box::new(LadderComponent::default()),
-                Vec::default(),
+                Box::new(LadderComponent::new(odds_format)),
+                // Ticks drive the flashing while suspended
+                vec![Sub::new(
+                    tuirealm::SubEventClause::Tick,
+                    tuirealm::SubClause::Always
+                )],
             )
             .is_ok());
 
+        assert!(app
+            .mount(Id::Race, Box::new(RaceComponent::default()), Vec::default())
+            .is_ok());
+
         assert!(app
             .mount(
                 Id::Status,
@@ -84,29 +260,265 @@ impl Model {
             .mount(
                 Id::Phantom,
                 Box::new(PhantomComponent::default()),
-                vec![Sub::new(
-                    tuirealm::SubEventClause::Keyboard(KeyEvent {
-                        code: Key::Esc,
-                        modifiers: KeyModifiers::NONE,
-                    }),
-                    tuirealm::SubClause::Always
-                )],
+                vec![
+                    Sub::new(
+                        tuirealm::SubEventClause::Keyboard(KeyEvent {
+                            code: Key::Esc,
+                            modifiers: KeyModifiers::NONE,
+                        }),
+                        tuirealm::SubClause::Always
+                    ),
+                    Sub::new(
+                        tuirealm::SubEventClause::Keyboard(KeyEvent {
+                            code: Key::Char('s'),
+                            modifiers: KeyModifiers::CONTROL,
+                        }),
+                        tuirealm::SubClause::Always
+                    ),
+                    Sub::new(tuirealm::SubEventClause::Tick, tuirealm::SubClause::Always),
+                    // User events match on the variant, the payload is ignored
+                    Sub::new(
+                        tuirealm::SubEventClause::User(UserEvent::Stream(StreamEvent::Closed(
+                            String::new()
+                        ))),
+                        tuirealm::SubClause::Always
+                    ),
+                ],
             )
             .is_ok());
         // Set active component
         assert!(app.active(&Id::Markets).is_ok());
 
-        return app;
+        app
     }
-}
 
-impl Default for Model {
-    fn default() -> Self {
-        Self {
-            app: Self::init_app(),
-            quit: false,
-            redraw: true,
-            terminal: TerminalBridge::new().expect("Cannot initialize terminal"),
+    /// The caches as they are now, to save for the next run. Closed markets
+    /// never change again and markets no longer subscribed would only be
+    /// stale on the next run, both are left out.
+    pub fn snapshot(&self) -> CacheSnapshot {
+        let subscribed: HashSet<&str> = self.subscriptions.markets().collect();
+        let mut snapshot = CacheSnapshot {
+            markets: self.markets.clone(),
+            orders: self.orders.clone(),
+        };
+        snapshot
+            .markets
+            .retain(|market| subscribed.contains(market.market_id.as_str()));
+        let connections: HashSet<usize> = self.subscriptions.connection_ids().collect();
+        snapshot
+            .markets
+            .retain_clocks(|connection| connections.contains(&connection));
+        snapshot.drop_closed();
+        snapshot
+    }
+
+    // Everything cached, closed and unsubscribed markets too, as json in the
+    // data dir to attach to a bug report
+    fn save_json_snapshot(&mut self) {
+        let snapshot = CacheSnapshot {
+            markets: self.markets.clone(),
+            orders: self.orders.clone(),
+        };
+        let saved = CacheSnapshot::default_path(SnapshotFormat::Json)
+            .and_then(|path| snapshot.save(&path).map(|()| path));
+        self.set_status(match saved {
+            Ok(path) => format!("Caches saved to {}", path.display()),
+            Err(e) => format!("Unable to save the caches: {}", e),
+        });
+    }
+
+    fn set_status(&mut self, status: String) {
+        self.status = status;
+        self.show_status();
+    }
+
+    fn show_status(&mut self) {
+        let worst = self
+            .latency
+            .iter()
+            .max_by_key(|(_, (_, latency))| latency.p99_ms);
+        let status = match worst {
+            Some((connection, (_, latency))) => {
+                format!("{} | {} on connection {}", self.status, latency, connection)
+            }
+            None => self.status.clone(),
+        };
+        assert!(self
+            .app
+            .attr(
+                &Id::Status,
+                Attribute::Title,
+                AttrValue::Title((status, Alignment::Center)),
+            )
+            .is_ok());
+    }
+
+    fn on_stream(&mut self, event: StreamEvent) {
+        match event {
+            StreamEvent::Message {
+                message: ResponseMessage::Connection(connection),
+                ..
+            } => self.set_status(format!("Connected {}", connection.connection_id)),
+            StreamEvent::Message {
+                message: ResponseMessage::Status(status),
+                ..
+            } if status.status_code == StatusCode::Failure => self.set_status(format!(
+                "Stream error {:?} {}",
+                status.error_code.unwrap_or(ErrorCode::Unknown),
+                status.error_message.unwrap_or_default()
+            )),
+            StreamEvent::Message {
+                connection,
+                message: ResponseMessage::Mcm(mcm),
+            } => {
+                for event in self.markets.update(connection, &mcm) {
+                    self.on_market(event);
+                }
+                let shown = mcm
+                    .mc
+                    .iter()
+                    .flatten()
+                    .any(|change| self.ladder_market.as_deref() == Some(change.id.as_str()));
+                if shown {
+                    self.show_ladder();
+                }
+            }
+            StreamEvent::Message {
+                message: ResponseMessage::Ocm(ocm),
+                ..
+            } => {
+                let notifications = self.orders.update(&ocm);
+                for notification in &notifications {
+                    match notification {
+                        OrderNotification::Order {
+                            market_id,
+                            runner,
+                            order,
+                        } => {
+                            self.subscriptions.add(Interest::Orders, market_id);
+                            self.set_status(format!(
+                                "Order {} {:?} {}@{} on {} in {} {:?}",
+                                order.id,
+                                order.side,
+                                order.s,
+                                order.p,
+                                runner.selection_id,
+                                market_id,
+                                order.status
+                            ));
+                        }
+                        OrderNotification::Position {
+                            market_id,
+                            runner,
+                            position,
+                        } => self.set_status(format!(
+                            "Position on {} in {}: win {:.2} lose {:.2}",
+                            runner.selection_id, market_id, position.if_win, position.if_lose
+                        )),
+                        OrderNotification::MarketClosed(market_id) => {
+                            self.subscriptions.remove(Interest::Orders, market_id);
+                            self.races.remove(market_id);
+                            self.set_status(format!("Orders in {} closed", market_id));
+                        }
+                    }
+                }
+                if !notifications.is_empty() {
+                    self.sync_subscriptions();
+                }
+            }
+            StreamEvent::Message {
+                message: ResponseMessage::Rcm(rcm),
+                ..
+            } => {
+                self.races.update(&rcm);
+                let shown = rcm
+                    .rc
+                    .iter()
+                    .flatten()
+                    .any(|change| self.ladder_market.as_deref() == Some(change.mid.as_str()));
+                if shown {
+                    self.show_race();
+                }
+            }
+            StreamEvent::Message { .. } => {}
+            StreamEvent::Stale { silent_for } => self.set_status(format!(
+                "Stream stale, no data for {:.1}s",
+                silent_for.as_secs_f32()
+            )),
+            StreamEvent::Disconnected {
+                reason,
+                attempt,
+                retry_in,
+            } => self.set_status(format!(
+                "Reconnecting in {:.1}s, attempt {}: {}",
+                retry_in.as_secs_f32(),
+                attempt,
+                reason
+            )),
+            StreamEvent::ConnectionsLow { available } => self.set_status(format!(
+                "Only {} stream connections left for this app key",
+                available
+            )),
+            StreamEvent::Unparsed { reason, .. } => {
+                self.set_status(format!("Skipped a frame from betfair, {}", reason))
+            }
+            StreamEvent::Latency {
+                connection,
+                latency,
+            } => {
+                self.latency.insert(connection, (Instant::now(), latency));
+                // Connections that closed or went quiet have nothing current to say
+                self.latency
+                    .retain(|_, (received, _)| received.elapsed() < LATENCY_KEPT);
+                self.show_status();
+            }
+            // A connection is done opening, put the markets waiting for it on it
+            StreamEvent::Opened { .. } | StreamEvent::OpenFailed(_) => self.sync_subscriptions(),
+            StreamEvent::Closed(reason) => self.set_status(format!("Stream closed: {}", reason)),
+        }
+    }
+
+    fn on_market(&mut self, event: MarketEvent) {
+        match event {
+            MarketEvent::Status { market_id, to, .. } => {
+                // Other markets only make it to the status bar
+                if self.ladder_market.as_deref() == Some(market_id.as_str()) {
+                    let suspended = to == MarketStatus::Suspended;
+                    assert!(self
+                        .app
+                        .attr(
+                            &Id::Ladder,
+                            Attribute::Custom(SUSPENDED),
+                            AttrValue::Flag(suspended)
+                        )
+                        .is_ok());
+                }
+                // Races of closed markets are done
+                if to == MarketStatus::Closed {
+                    self.races.remove(&market_id);
+                    self.show_race();
+                }
+                self.set_status(format!("Market {} {:?}", market_id, to));
+            }
+            MarketEvent::RunnerStatus {
+                market_id,
+                runner,
+                to: RunnerStatus::Removed,
+                ..
+            } => self.set_status(format!(
+                "Runner {} removed from {}",
+                runner.selection_id, market_id
+            )),
+            MarketEvent::AdjustmentFactor {
+                market_id,
+                runner,
+                to: Some(factor),
+                ..
+            } => self.set_status(format!(
+                "Adjustment factor of runner {} in {} is now {}",
+                runner.selection_id, market_id, factor
+            )),
+            _ => {}
         }
     }
 }
@@ -121,6 +533,20 @@ impl Update<Msg> for Model {
                     self.quit = true;
                     None
                 }
+                Msg::SaveSnapshot => {
+                    self.save_json_snapshot();
+                    None
+                }
+                Msg::Stream(event) => {
+                    self.on_stream(event);
+                    None
+                }
+                Msg::Clock => {
+                    if self.sync_failed {
+                        self.sync_subscriptions();
+                    }
+                    None
+                }
                 _ => None,
             }
         } else {
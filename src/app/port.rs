@@ -0,0 +1,30 @@
+use std::sync::mpsc::Receiver;
+
+use tuirealm::{
+    listener::{ListenerResult, Poll},
+    Event,
+};
+
+use crate::{stream::StreamEvent, UserEvent};
+
+/// Forwards events from the stream thread into the tuirealm event loop
+pub struct StreamPort {
+    events: Receiver<StreamEvent>,
+}
+
+impl StreamPort {
+    pub fn new(events: Receiver<StreamEvent>) -> Self {
+        Self { events }
+    }
+}
+
+impl Poll<UserEvent> for StreamPort {
+    fn poll(&mut self) -> ListenerResult<Option<Event<UserEvent>>> {
+        // A closed channel just means the stream is gone, the tui keeps running
+        Ok(self
+            .events
+            .try_recv()
+            .ok()
+            .map(|event| Event::User(UserEvent::Stream(event))))
+    }
+}
@@ -1,28 +1,165 @@
-use super::Msg;
-use tui_realm_stdlib::Container;
-use tuirealm::{command::CmdResult, props::Alignment, Component, MockComponent, NoUserEvent};
+use super::{Msg, UserEvent};
+use crate::{odds::OddsFormat, price::Price};
+use tui_realm_stdlib::Table;
+use tuirealm::{
+    command::{Cmd, CmdResult},
+    props::{Alignment, Color, PropPayload, PropValue, TextSpan},
+    tui::layout::Rect,
+    AttrValue, Attribute, Component, Event, Frame, MockComponent, State,
+};
+
+/// Set while the market is suspended, the ladder flashes until it is cleared
+pub const SUSPENDED: &str = "suspended";
+/// The rows to show, a `PropPayload::Linked` of `Tup3` price, size to back and size to lay
+pub const LEVELS: &str = "levels";
+
+const BACKGROUND: Color = Color::Green;
+const FLASH: Color = Color::Red;
 
-#[derive(MockComponent)]
 pub struct LadderComponent {
-    component: Container,
+    component: Table,
+    odds_format: OddsFormat,
 }
 
 impl Default for LadderComponent {
     fn default() -> Self {
+        Self::new(OddsFormat::default())
+    }
+}
+
+impl LadderComponent {
+    /// A ladder showing its prices in `odds_format`
+    pub fn new(odds_format: OddsFormat) -> Self {
         Self {
-            component: Container::default()
-                .background(tuirealm::props::Color::Green)
+            component: Table::default()
+                .background(BACKGROUND)
                 .foreground(tuirealm::props::Color::Yellow)
-                .title("Ladder", Alignment::Center),
+                .title(format!("Ladder ({})", odds_format), Alignment::Center)
+                .headers(&["To back", "Odds", "To lay"])
+                .widths(&[35, 30, 35]),
+            odds_format,
         }
     }
-}
 
-impl Component<Msg, NoUserEvent> for LadderComponent {
-    fn on(&mut self, ev: tuirealm::Event<NoUserEvent>) -> Option<Msg> {
-        let _ = match ev {
-            _ => CmdResult::None,
+    /// How a price on the ladder reads
+    pub fn label(&self, price: Price) -> String {
+        self.odds_format.format(price)
+    }
+
+    // One row per level, prices off the ladder are shown as they came
+    fn rows(&self, levels: &PropPayload) -> Vec<Vec<TextSpan>> {
+        let size = |size: f64| match size {
+            0. => String::new(),
+            size => format!("{:.2}", size),
+        };
+        let PropPayload::Linked(levels) = levels else {
+            return Vec::new();
         };
+        levels
+            .iter()
+            .filter_map(|level| match level {
+                PropPayload::Tup3((
+                    PropValue::F64(price),
+                    PropValue::F64(back),
+                    PropValue::F64(lay),
+                )) => Some(vec![
+                    TextSpan::from(size(*back)),
+                    TextSpan::from(
+                        Price::try_from(*price)
+                            .map(|price| self.label(price))
+                            .unwrap_or_else(|_| price.to_string()),
+                    ),
+                    TextSpan::from(size(*lay)),
+                ]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Swap colours on every tick while suspended, settle back once it isn't
+    fn flash(&mut self) {
+        let suspended = self
+            .query(Attribute::Custom(SUSPENDED))
+            .is_some_and(|flag| flag == AttrValue::Flag(true));
+        let background = match self.query(Attribute::Background) {
+            Some(AttrValue::Color(BACKGROUND)) if suspended => FLASH,
+            _ => BACKGROUND,
+        };
+        self.attr(Attribute::Background, AttrValue::Color(background));
+    }
+}
+
+impl MockComponent for LadderComponent {
+    fn view(&mut self, frame: &mut Frame, area: Rect) {
+        self.component.view(frame, area);
+    }
+
+    fn query(&self, attr: Attribute) -> Option<AttrValue> {
+        self.component.query(attr)
+    }
+
+    // Levels are turned into the table rows here, in the selected odds format
+    fn attr(&mut self, attr: Attribute, value: AttrValue) {
+        if let (Attribute::Custom(LEVELS), AttrValue::Payload(levels)) = (attr, &value) {
+            let rows = self.rows(levels);
+            self.component
+                .attr(Attribute::Content, AttrValue::Table(rows));
+        }
+        self.component.attr(attr, value);
+    }
+
+    fn state(&self) -> State {
+        self.component.state()
+    }
+
+    fn perform(&mut self, cmd: Cmd) -> CmdResult {
+        self.component.perform(cmd)
+    }
+}
+
+impl Component<Msg, UserEvent> for LadderComponent {
+    fn on(&mut self, ev: Event<UserEvent>) -> Option<Msg> {
+        if ev == Event::Tick {
+            self.flash();
+        }
         Some(Msg::None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::LinkedList;
+
+    #[test]
+    fn prices_read_in_the_selected_format() {
+        let mut ladder = LadderComponent::new(OddsFormat::Fractional);
+        let level = |price, back, lay| {
+            PropPayload::Tup3((
+                PropValue::F64(price),
+                PropValue::F64(back),
+                PropValue::F64(lay),
+            ))
+        };
+        let levels = LinkedList::from([level(3., 0., 12.5), level(2., 20., 0.)]);
+        ladder.attr(
+            Attribute::Custom(LEVELS),
+            AttrValue::Payload(PropPayload::Linked(levels)),
+        );
+
+        let rows: Vec<Vec<String>> = ladder
+            .query(Attribute::Content)
+            .unwrap()
+            .unwrap_table()
+            .into_iter()
+            .map(|row| row.into_iter().map(|span| span.content).collect())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec![String::new(), String::from("2/1"), String::from("12.50")],
+                vec![String::from("20.00"), String::from("1/1"), String::new()],
+            ]
+        );
+    }
+}
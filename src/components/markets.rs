@@ -1,6 +1,6 @@
-use super::Msg;
+use super::{Msg, UserEvent};
 use tui_realm_stdlib::Container;
-use tuirealm::{command::CmdResult, props::Alignment, Component, MockComponent, NoUserEvent};
+use tuirealm::{props::Alignment, Component, MockComponent};
 
 #[derive(MockComponent)]
 pub struct MarketsComponent {
@@ -18,11 +18,8 @@ impl Default for MarketsComponent {
     }
 }
 
-impl Component<Msg, NoUserEvent> for MarketsComponent {
-    fn on(&mut self, ev: tuirealm::Event<NoUserEvent>) -> Option<Msg> {
-        let _ = match ev {
-            _ => CmdResult::None,
-        };
+impl Component<Msg, UserEvent> for MarketsComponent {
+    fn on(&mut self, _ev: tuirealm::Event<UserEvent>) -> Option<Msg> {
         Some(Msg::None)
     }
 }
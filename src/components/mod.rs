@@ -1,12 +1,14 @@
-use super::Msg;
+use super::{Msg, UserEvent};
 
 mod ladder;
 mod markets;
 mod phantom;
+mod race;
 mod status;
 
 // exports
-pub use ladder::LadderComponent;
+pub use ladder::{LadderComponent, LEVELS, SUSPENDED};
 pub use markets::MarketsComponent;
 pub use phantom::PhantomComponent;
+pub use race::RaceComponent;
 pub use status::StatusComponent;
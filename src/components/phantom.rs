@@ -1,28 +1,26 @@
-use super::Msg;
+use super::{Msg, UserEvent};
 use tui_realm_stdlib::Phantom;
 use tuirealm::{
     command::CmdResult,
-    event::{Key, KeyEvent},
-    Component, Event, MockComponent, NoUserEvent,
+    event::{Key, KeyEvent, KeyModifiers},
+    Component, Event, MockComponent,
 };
 
-#[derive(MockComponent)]
+#[derive(MockComponent, Default)]
 pub struct PhantomComponent {
     component: Phantom,
 }
 
-impl Default for PhantomComponent {
-    fn default() -> Self {
-        Self {
-            component: Phantom::default(),
-        }
-    }
-}
-
-impl Component<Msg, NoUserEvent> for PhantomComponent {
-    fn on(&mut self, ev: tuirealm::Event<NoUserEvent>) -> Option<Msg> {
+impl Component<Msg, UserEvent> for PhantomComponent {
+    fn on(&mut self, ev: tuirealm::Event<UserEvent>) -> Option<Msg> {
         let _ = match ev {
             Event::Keyboard(KeyEvent { code: Key::Esc, .. }) => return Some(Msg::AppClose),
+            Event::Keyboard(KeyEvent {
+                code: Key::Char('s'),
+                modifiers: KeyModifiers::CONTROL,
+            }) => return Some(Msg::SaveSnapshot),
+            Event::User(UserEvent::Stream(event)) => return Some(Msg::Stream(event)),
+            Event::Tick => return Some(Msg::Clock),
             _ => CmdResult::None,
         };
         Some(Msg::None)
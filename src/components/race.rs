@@ -0,0 +1,77 @@
+use super::{Msg, UserEvent};
+use crate::stream::Race;
+use tui_realm_stdlib::Table;
+use tuirealm::{
+    props::{Alignment, TextSpan},
+    Component, MockComponent,
+};
+
+/// Running order of the race in the ladder, fed through `Attribute::Content`
+#[derive(MockComponent)]
+pub struct RaceComponent {
+    component: Table,
+}
+
+impl Default for RaceComponent {
+    fn default() -> Self {
+        Self {
+            component: Table::default()
+                .background(tuirealm::props::Color::Green)
+                .foreground(tuirealm::props::Color::Yellow)
+                .title("Race", Alignment::Center)
+                .headers(&["#", "Runner", "To go"])
+                .widths(&[20, 45, 35]),
+        }
+    }
+}
+
+impl RaceComponent {
+    /// One row per runner in running order, with the metres it has left
+    pub fn rows(race: &Race) -> Vec<Vec<TextSpan>> {
+        race.positions()
+            .into_iter()
+            .enumerate()
+            .map(|(position, runner)| {
+                vec![
+                    TextSpan::from((position + 1).to_string()),
+                    TextSpan::from(runner.id.to_string()),
+                    TextSpan::from(
+                        runner
+                            .prg
+                            .map(|left| format!("{:.0}m", left))
+                            .unwrap_or_default(),
+                    ),
+                ]
+            })
+            .collect()
+    }
+}
+
+impl Component<Msg, UserEvent> for RaceComponent {
+    fn on(&mut self, _ev: tuirealm::Event<UserEvent>) -> Option<Msg> {
+        Some(Msg::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::RaceCache;
+
+    #[test]
+    fn leader_first_with_distance_left() {
+        let mut races = RaceCache::new();
+        races.update(
+            &serde_json::from_str(
+                r#"{"pt":1,"rc":[{"id":"r.1","mid":"1.1","rpc":{"ft":1,"ord":[8,7]},"rrc":[{"ft":1,"id":7,"prg":412.6},{"ft":1,"id":8}]}]}"#,
+            )
+            .unwrap(),
+        );
+
+        let rows: Vec<Vec<String>> = RaceComponent::rows(races.race("1.1").unwrap())
+            .into_iter()
+            .map(|row| row.into_iter().map(|span| span.content).collect())
+            .collect();
+        assert_eq!(rows, [["1", "8", ""], ["2", "7", "413m"]]);
+    }
+}
@@ -1,97 +1,431 @@
-use std::collections::{btree_map::Entry, BTreeMap};
+use std::collections::BTreeMap;
 
-use num::FromPrimitive;
+use crate::{
+    price::{Price, TICKS},
+    stream::model::RunnerChange,
+};
 
 // LOB - limit order book consist of two sides, a side consist of multiple levels.
 // a level is volume and an odds, odds is an array containing timestamps for each
 // update at that level.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Stake {
-    odds: Odds,
-    timestamp: u8,
-    amount: u8,
+    odds: Price,
+    /// Publish time of the update in milliseconds since epoch
+    timestamp: i64,
+    amount: f64,
+}
+
+impl Stake {
+    pub fn new(odds: Price, amount: f64, timestamp: i64) -> Self {
+        Self {
+            odds,
+            timestamp,
+            amount,
+        }
+    }
+
+    pub fn odds(&self) -> Price {
+        self.odds
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn amount(&self) -> f64 {
+        self.amount
+    }
 }
-type Odds = u8;
-// Is an ordered map
+
+/// Which side of the book, back is what is available to back and lay what
+/// is available to lay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Side {
-    Back(Stake),
-    Lay(Stake),
+    Back,
+    Lay,
+}
+
+/// What is on offer at one price
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    pub price: Price,
+    pub size: f64,
 }
 
-// fractional ods num crate
-// decimal odds not Ord?
-// moneyline aka AmericanOdds
-// TODO use Rational32 as key since float can not be key
 #[derive(Debug, Clone, Default)]
 pub struct MapLadder {
     // TODO also need some match value here?
-    back: BTreeMap<Odds, Vec<Stake>>,
-    lay: BTreeMap<Odds, Vec<Stake>>,
+    back: BTreeMap<Price, Vec<Stake>>,
+    lay: BTreeMap<Price, Vec<Stake>>,
 }
 
 impl MapLadder {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Default::default()
     }
+
+    fn book(&self, side: Side) -> &BTreeMap<Price, Vec<Stake>> {
+        match side {
+            Side::Back => &self.back,
+            Side::Lay => &self.lay,
+        }
+    }
+
+    // Levels with something on them from the best price outwards, the last
+    // stake at a price is the current size
+    fn levels(&self, side: Side) -> Box<dyn Iterator<Item = Level> + '_> {
+        let level = |(price, stakes): (&Price, &Vec<Stake>)| {
+            stakes
+                .last()
+                .filter(|stake| stake.amount > 0.)
+                .map(|stake| Level {
+                    price: *price,
+                    size: stake.amount,
+                })
+        };
+        match side {
+            Side::Back => Box::new(self.back.iter().rev().filter_map(level)),
+            Side::Lay => Box::new(self.lay.iter().filter_map(level)),
+        }
+    }
 }
 
 impl Ladder for MapLadder {
-    // TODO need to seperate back/lay
-    fn update(&mut self, side: Side) {
+    fn update(&mut self, side: Side, stake: Stake) {
         match side {
-            Side::Back(stake) => {
-                self.back
-                    .entry(stake.odds)
-                    // .and_modify(|curr| curr.push(stake))
-                    .or_default()
-                    .push(stake);
-            }
-            Side::Lay(stake) => {
-                self.lay.entry(stake.odds).or_default().push(stake);
-                // .and_modify(|curr| curr.push(stake))
-                // .or_insert(vec![stake]);
+            Side::Back => &mut self.back,
+            Side::Lay => &mut self.lay,
+        }
+        .entry(stake.odds)
+        .or_default()
+        .push(stake);
+    }
+
+    fn top(&self, side: Side, n: usize) -> Vec<Level> {
+        self.levels(side).take(n).collect()
+    }
+
+    fn size_at(&self, side: Side, price: Price) -> f64 {
+        self.book(side)
+            .get(&price)
+            .and_then(|stakes| stakes.last())
+            .map_or(0., |stake| stake.amount)
+    }
+
+    fn depth_to(&self, side: Side, price: Price) -> f64 {
+        self.levels(side)
+            .take_while(|level| !is_behind(side, level.price, price))
+            .map(|level| level.size)
+            .sum()
+    }
+}
+
+/// A slot for every tick on each side, an update is a store and finding the
+/// best price a scan over neighbouring slots. Only the current size is kept.
+#[derive(Debug, Clone)]
+pub struct TickLadder {
+    back: [f64; TICKS],
+    lay: [f64; TICKS],
+    // Tick of the best level on each side, kept up to date on every update
+    best_back: Option<usize>,
+    best_lay: Option<usize>,
+}
+
+impl Default for TickLadder {
+    fn default() -> Self {
+        Self {
+            back: [0.; TICKS],
+            lay: [0.; TICKS],
+            best_back: None,
+            best_lay: None,
+        }
+    }
+}
+
+impl TickLadder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn sizes(&self, side: Side) -> &[f64; TICKS] {
+        match side {
+            Side::Back => &self.back,
+            Side::Lay => &self.lay,
+        }
+    }
+
+    fn best(&self, side: Side) -> Option<usize> {
+        match side {
+            Side::Back => self.best_back,
+            Side::Lay => self.best_lay,
+        }
+    }
+
+    fn level(&self, side: Side, tick: usize) -> Option<Level> {
+        let size = self.sizes(side)[tick];
+        Price::from_tick(tick)
+            .filter(|_| size > 0.)
+            .map(|price| Level { price, size })
+    }
+}
+
+impl Ladder for TickLadder {
+    fn update(&mut self, side: Side, stake: Stake) {
+        let tick = stake.odds.tick();
+        let (sizes, best) = match side {
+            Side::Back => (&mut self.back, &mut self.best_back),
+            Side::Lay => (&mut self.lay, &mut self.best_lay),
+        };
+        sizes[tick] = stake.amount;
+        if stake.amount > 0. {
+            if best.is_none_or(|best| is_behind(side, best, tick)) {
+                *best = Some(tick);
             }
+        } else if *best == Some(tick) {
+            // The best went away, the next one is the closest level behind it
+            *best = match side {
+                Side::Back => sizes[..tick].iter().rposition(|size| *size > 0.),
+                Side::Lay => sizes[tick + 1..]
+                    .iter()
+                    .position(|size| *size > 0.)
+                    .map(|offset| tick + 1 + offset),
+            };
+        }
+    }
+
+    fn top(&self, side: Side, n: usize) -> Vec<Level> {
+        let level = |tick| self.level(side, tick);
+        match (side, self.best(side)) {
+            (_, None) => Vec::new(),
+            (Side::Back, Some(best)) => (0..=best).rev().filter_map(level).take(n).collect(),
+            (Side::Lay, Some(best)) => (best..TICKS).filter_map(level).take(n).collect(),
         }
     }
+
+    fn size_at(&self, side: Side, price: Price) -> f64 {
+        self.sizes(side)[price.tick()]
+    }
+
+    fn depth_to(&self, side: Side, price: Price) -> f64 {
+        let sizes = self.sizes(side);
+        let limit = price.tick();
+        match (side, self.best(side)) {
+            (Side::Back, Some(best)) if limit <= best => sizes[limit..=best].iter().sum(),
+            (Side::Lay, Some(best)) if limit >= best => sizes[best..=limit].iter().sum(),
+            _ => 0.,
+        }
+    }
+
+    fn best_back(&self) -> Option<Level> {
+        self.level(Side::Back, self.best_back?)
+    }
+
+    fn best_lay(&self) -> Option<Level> {
+        self.level(Side::Lay, self.best_lay?)
+    }
 }
 
+// Whether `price` is further from the best than `limit` on `side`
+fn is_behind<T: Ord>(side: Side, price: T, limit: T) -> bool {
+    match side {
+        Side::Back => price < limit,
+        Side::Lay => price > limit,
+    }
+}
+
+/// Prices available to back and lay on one runner. Back prices are best at
+/// the top, lay prices at the bottom.
 pub trait Ladder {
-    // Update orderbook at level
-    // TODO take timestamp odds and volue
-    // Update a value in the ladder, if the odds already
-    // exist append if not create the initial entry.
-    fn update(&mut self, side: Side);
-    // Get the best n back or lay, observe that all 0 stake values must be ignored.
-    // Must check the lasp value in the vec to get the most recent value.
-    // fn top(&self, n: u8) -> Stake;
+    /// Set the size at a price, a size of 0 takes the level away
+    fn update(&mut self, side: Side, stake: Stake);
+
+    /// The best `n` levels on a side, best first, empty levels skipped
+    fn top(&self, side: Side, n: usize) -> Vec<Level>;
+
+    /// Size at a price, 0 when there is none
+    fn size_at(&self, side: Side, price: Price) -> f64;
+
+    /// Everything from the best price on a side up to and including `price`,
+    /// what an order at `price` could match against
+    fn depth_to(&self, side: Side, price: Price) -> f64;
+
+    /// The highest price with something to back
+    fn best_back(&self) -> Option<Level> {
+        self.top(Side::Back, 1).pop()
+    }
+
+    /// The lowest price with something to lay
+    fn best_lay(&self) -> Option<Level> {
+        self.top(Side::Lay, 1).pop()
+    }
+
+    /// Ticks from the best back up to the best lay, 1 when they touch
+    fn spread(&self) -> Option<isize> {
+        Some(self.best_back()?.price.ticks_to(self.best_lay()?.price))
+    }
+
+    /// Decimal odds halfway between the best back and best lay
+    fn mid(&self) -> Option<f64> {
+        let (back, lay) = (self.best_back()?, self.best_lay()?);
+        Some((back.price.odds() + lay.price.odds()) / 2.)
+    }
+
+    /// Apply what is available to back and lay from a runner change
+    /// published at `pt`
+    fn apply(&mut self, change: &RunnerChange, pt: i64) {
+        for (side, levels) in [(Side::Back, &change.atb), (Side::Lay, &change.atl)] {
+            for [odds, size] in levels.iter().flatten() {
+                // Betfair only sends prices on the table
+                if let Ok(price) = Price::new(*odds) {
+                    self.update(side, Stake::new(price, *size, pt));
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    fn price(odds: f64) -> Price {
+        Price::new(odds).unwrap()
+    }
+
+    // 1.99 to 2.04 to back and 2.06 to 2.1 to lay, a gap at 2.02 and 2.08
+    fn book() -> MapLadder {
+        let mut sut = MapLadder::new();
+        for (odds, amount) in [(2., 30.), (2.02, 0.), (2.04, 10.), (1.99, 5.)] {
+            sut.update(Side::Back, Stake::new(price(odds), amount, 1));
+        }
+        for (odds, amount) in [(2.06, 8.), (2.08, 0.), (2.1, 20.)] {
+            sut.update(Side::Lay, Stake::new(price(odds), amount, 1));
+        }
+        sut
+    }
 
     #[test]
     fn update_ladder() {
         let mut sut = MapLadder::new();
-        sut.update(Side::Back(Stake {
-            odds: 3,
-            timestamp: 4,
-            amount: 5,
-        }));
-        let result = sut.back.get(&3);
+        let odds = price(3.);
+        sut.update(Side::Back, Stake::new(odds, 5., 4));
+        let result = sut.back.get(&odds);
         assert!(result.is_some(), "unable to insert update in ladder");
         assert!(result.unwrap().last().is_some(), "no element inserted");
+
+        // The history is kept, the last one counts
+        sut.update(Side::Back, Stake::new(odds, 7., 5));
+        assert_eq!(sut.back[&odds].len(), 2);
+        assert_eq!(sut.size_at(Side::Back, odds), 7.);
+        assert_eq!(sut.size_at(Side::Lay, odds), 0.);
+    }
+
+    #[test]
+    fn best_and_top() {
+        let sut = book();
+        assert_eq!(
+            sut.best_back(),
+            Some(Level {
+                price: price(2.04),
+                size: 10.
+            })
+        );
+        assert_eq!(sut.best_lay().unwrap().price, price(2.06));
+        let prices = |levels: Vec<Level>| -> Vec<f64> {
+            levels.iter().map(|level| level.price.odds()).collect()
+        };
+        assert_eq!(prices(sut.top(Side::Back, 3)), [2.04, 2., 1.99]);
+        assert_eq!(prices(sut.top(Side::Lay, 5)), [2.06, 2.1]);
+        assert!(sut.top(Side::Back, 0).is_empty());
+
+        // Taking the best level away moves the best down
+        let mut sut = sut;
+        sut.update(Side::Back, Stake::new(price(2.04), 0., 2));
+        assert_eq!(sut.best_back().unwrap().price, price(2.));
+        assert!(MapLadder::new().best_lay().is_none());
+    }
+
+    #[test]
+    fn depth_spread_and_mid() {
+        let sut = book();
+        assert_eq!(sut.depth_to(Side::Back, price(2.)), 40.);
+        assert_eq!(sut.depth_to(Side::Back, price(1.5)), 45.);
+        assert_eq!(sut.depth_to(Side::Back, price(2.5)), 0.);
+        assert_eq!(sut.depth_to(Side::Lay, price(2.08)), 8.);
+        assert_eq!(sut.depth_to(Side::Lay, price(2.1)), 28.);
+
+        assert_eq!(sut.spread(), Some(1));
+        assert_eq!(sut.mid(), Some(2.05));
+        let mut one_sided = MapLadder::new();
+        one_sided.update(Side::Lay, Stake::new(price(2.), 1., 1));
+        assert_eq!(one_sided.spread(), None);
+        assert_eq!(one_sided.mid(), None);
+    }
+
+    #[test]
+    fn tick_ladder_book() {
+        let mut sut = TickLadder::new();
+        for level in book().top(Side::Back, TICKS) {
+            sut.update(Side::Back, Stake::new(level.price, level.size, 1));
+        }
+        for level in book().top(Side::Lay, TICKS) {
+            sut.update(Side::Lay, Stake::new(level.price, level.size, 1));
+        }
+        assert_eq!(sut.best_back().unwrap().price, price(2.04));
+        assert_eq!(sut.depth_to(Side::Back, price(2.)), 40.);
+        assert_eq!(sut.depth_to(Side::Lay, price(2.1)), 28.);
+        assert_eq!(sut.mid(), Some(2.05));
+
+        // Emptying the best levels walks the best back to what is left
+        sut.update(Side::Back, Stake::new(price(2.04), 0., 2));
+        sut.update(Side::Lay, Stake::new(price(2.06), 0., 2));
+        assert_eq!(sut.best_back().unwrap().price, price(2.));
+        assert_eq!(sut.best_lay().unwrap().price, price(2.1));
+        sut.update(Side::Lay, Stake::new(price(2.1), 0., 3));
+        assert_eq!(sut.best_lay(), None);
+        assert!(sut.top(Side::Lay, 3).is_empty());
     }
-    // #[test]
-    // fn fractional_to_decimal() {
-    //     let odds = num::Rational32::from_f32(1.2 - 1.);
-    // }
 
-    // TODO read up on https://github.com/pnxenopoulos/implied
     #[test]
-    fn decimal_to_fractional() {
-        let odds = num::Rational32::from_f32(1.2 - 1.).unwrap();
-        assert_eq!(odds.numer(), &1);
-        assert_eq!(odds.denom(), &5);
+    fn apply_runner_change() {
+        let change: RunnerChange =
+            serde_json::from_str(r#"{"id":7,"atb":[[1.95,12.5],[1.94,0]],"atl":[[1.96,40]]}"#)
+                .unwrap();
+        let mut sut = TickLadder::new();
+        sut.apply(&change, 1);
+        assert_eq!(sut.best_back().unwrap().size, 12.5);
+        assert_eq!(sut.spread(), Some(1));
+    }
+
+    fn updates() -> impl Strategy<Value = Vec<(bool, usize, f64)>> {
+        // Around a few ticks so levels get emptied and refilled
+        let size = prop_oneof![Just(0.), (1..100u32).prop_map(f64::from)];
+        prop::collection::vec((any::<bool>(), 100..110usize, size), 1..200)
+    }
+
+    proptest! {
+        #[test]
+        fn tick_ladder_agrees_with_map_ladder(updates in updates()) {
+            let mut map = MapLadder::new();
+            let mut tick = TickLadder::new();
+            for (pt, (back, at, size)) in updates.into_iter().enumerate() {
+                let side = if back { Side::Back } else { Side::Lay };
+                let stake = Stake::new(Price::from_tick(at).unwrap(), size, pt as i64);
+                map.update(side, stake);
+                tick.update(side, stake);
+
+                for side in [Side::Back, Side::Lay] {
+                    prop_assert_eq!(tick.top(side, 5), map.top(side, 5));
+                    let at = Price::from_tick(at).unwrap();
+                    prop_assert_eq!(tick.size_at(side, at), map.size_at(side, at));
+                    prop_assert_eq!(tick.depth_to(side, at), map.depth_to(side, at));
+                }
+                prop_assert_eq!(tick.best_back(), map.best_back());
+                prop_assert_eq!(tick.best_lay(), map.best_lay());
+                prop_assert_eq!(tick.spread(), map.spread());
+            }
+        }
     }
 }
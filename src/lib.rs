@@ -0,0 +1,100 @@
+pub mod app;
+pub mod components;
+pub mod ladder;
+pub mod odds;
+pub mod price;
+pub mod proxy;
+pub mod rest;
+pub mod stream;
+
+use color_eyre::eyre::{self, Context};
+use std::{cmp::Ordering, mem, path::PathBuf};
+
+use directories::ProjectDirs;
+use stream::StreamEvent;
+
+// What messages the app can handle, must have `PartialEq`
+#[derive(Debug, PartialEq)]
+pub enum Msg {
+    AppClose,
+    /// Save the caches as json, for bug reports
+    SaveSnapshot,
+    Stream(StreamEvent),
+    Clock,
+    DigitCounterChanged(isize),
+    DigitCounterBlur,
+    LetterCounterChanged(isize),
+    LetterCounterBlur,
+    None,
+}
+
+// Let's define the component ids for our application
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub enum Id {
+    Ladder,
+    Markets,
+    Race,
+    Status,
+    Phantom,
+}
+
+// Events from outside of the tui, pushed in through a port
+#[derive(Debug, Clone)]
+pub enum UserEvent {
+    Stream(StreamEvent),
+}
+
+// tuirealm matches subscriptions with `PartialEq`, compare on the variant only
+// so a component can subscribe to every event of a kind.
+impl PartialEq for UserEvent {
+    fn eq(&self, other: &Self) -> bool {
+        mem::discriminant(self) == mem::discriminant(other)
+    }
+}
+
+impl Eq for UserEvent {}
+
+impl PartialOrd for UserEvent {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self == other).then_some(Ordering::Equal)
+    }
+}
+
+pub fn get_data_dir() -> eyre::Result<PathBuf> {
+    let directory = if let Some(proj_dirs) = ProjectDirs::from("com", "flimmer", "bfg") {
+        proj_dirs.data_local_dir().to_path_buf()
+    } else {
+        return Err(eyre::eyre!("Unable to find data directory for bfg"));
+    };
+    Ok(directory)
+}
+
+pub fn get_config_dir() -> eyre::Result<PathBuf> {
+    let directory = if let Some(proj_dirs) = ProjectDirs::from("com", "flimmer", "bfg") {
+        proj_dirs.config_local_dir().to_path_buf()
+    } else {
+        return Err(eyre::eyre!("Unable to find config directory for bfg"));
+    };
+    Ok(directory)
+}
+
+#[derive(Debug)]
+pub struct ConnectionConfig {
+    pub app_key: String,
+    pub password: String,
+    pub username: String,
+}
+
+impl ConnectionConfig {
+    pub fn new() -> eyre::Result<Self> {
+        let username = std::env::var("BFG_USERNAME").wrap_err("BFG_USERNAME not set in env")?;
+        let password = std::env::var("BFG_PASSWORD").wrap_err("BFG_PASSWORD not set in env")?;
+        let app_key = std::env::var("BFG_APP_KEY").wrap_err("BFG_APP_KEY not set in env")?;
+
+        Ok(ConnectionConfig {
+            username,
+            password,
+            app_key,
+        })
+    }
+}
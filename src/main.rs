@@ -1,154 +1,154 @@
-mod app;
-mod components;
-mod rest;
-mod stream;
-
-use clap::Parser;
-use color_eyre::{
-    eyre::{self, Context, Ok},
-    Section,
+use bfg::{
+    app::model::Model,
+    get_config_dir,
+    odds::OddsFormat,
+    proxy::Proxy,
+    rest,
+    stream::{
+        CacheSnapshot, MarketDataField, MarketDataFilter, MarketFilter, MarketSubscriptionMessage,
+        OrderSubscriptionMessage, SessionConfig, SnapshotFormat, StreamEndpoint, StreamSession,
+        SubscriptionManager,
+    },
+    ConnectionConfig, Id,
 };
-use std::path::PathBuf;
-
-use app::model::Model;
-use directories::ProjectDirs;
-use tuirealm::{AttrValue, Attribute, PollStrategy, Update};
-
-use crate::stream::{AuthenticationMessage, LinesCodec};
-
-// What messages the app can handle, must have `PartialEq`
-#[derive(Debug, PartialEq)]
-pub enum Msg {
-    AppClose,
-    Clock,
-    DigitCounterChanged(isize),
-    DigitCounterBlur,
-    LetterCounterChanged(isize),
-    LetterCounterBlur,
-    None,
-}
-
-// Let's define the component ids for our application
-#[derive(Debug, Eq, PartialEq, Clone, Hash)]
-pub enum Id {
-    Ladder,
-    Markets,
-    Status,
-    Phantom,
-}
+use clap::Parser;
+use color_eyre::eyre;
+use std::sync::{mpsc, Arc};
+use tuirealm::{props::Alignment, AttrValue, Attribute, PollStrategy, Update};
 
 #[derive(Parser, Debug)]
 #[command(version = "1", about = "a betfair trading tui")]
 struct Args {
     #[arg(short, long, default_value_t = 1000)]
     app_tick_rate: u64,
+    /// How the ladder shows odds
+    #[arg(short, long, value_enum, default_value_t = OddsFormat::Decimal)]
+    odds_format: OddsFormat,
+    /// Market to show in the ladder
+    #[arg(short, long)]
+    market: Option<String>,
 }
 
-fn get_data_dir() -> eyre::Result<PathBuf> {
-    let directory = if let Some(proj_dirs) = ProjectDirs::from("com", "flimmer", "bfg") {
-        proj_dirs.data_local_dir().to_path_buf()
-    } else {
-        return Err(eyre::eyre!("Unable to find data directory for bfg"));
-    };
-    Ok(directory)
-}
-
-fn get_config_dir() -> eyre::Result<PathBuf> {
-    let directory = if let Some(proj_dirs) = ProjectDirs::from("com", "flimmer", "bfg") {
-        proj_dirs.config_local_dir().to_path_buf()
-    } else {
-        return Err(eyre::eyre!("Unable to find config directory for bfg"));
-    };
-    Ok(directory)
-}
-
-#[derive(Debug)]
-pub struct ConnectionConfig {
-    pub app_key: String,
-    pub password: String,
-    pub username: String,
-}
-
-impl ConnectionConfig {
-    pub fn new() -> eyre::Result<Self> {
-        let username = std::env::var("BFG_USERNAME").wrap_err("BFG_USERNAME not set in env")?;
-        let password = std::env::var("BFG_PASSWORD").wrap_err("BFG_PASSWORD not set in env")?;
-        let app_key = std::env::var("BFG_APP_KEY").wrap_err("BFG_APP_KEY not set in env")?;
-
-        Ok(ConnectionConfig {
-            username,
-            password,
-            app_key,
-        })
-    }
-}
-
-fn main() -> eyre::Result<()> {
-    let args = Args::parse();
-    let conf = ConnectionConfig::new()?;
+fn login(conf: &ConnectionConfig) -> eyre::Result<String> {
     let login_res = rest::login(
         &conf.app_key,
         &conf.username,
         &conf.password,
         get_config_dir()?,
     )?;
-    let mut s = LinesCodec::new()?;
-    let auth_msg = AuthenticationMessage::new(&conf.app_key, &login_res.session_token.unwrap());
-
-    let res = s.send_message(auth_msg)?;
-    let res = s.read_message()?;
-    println!("{:?}", res);
-    let res = s.read_message()?;
-    println!("{:?}", res);
-    let res = s.read_message()?;
-    println!("{:?}", res);
-
-    Ok(())
+    login_res
+        .session_token
+        .ok_or_else(|| eyre::eyre!("login failed: {:?}", login_res.login_status))
+}
 
-    // // Setup model
-    // let mut model = Model::default();
+fn main() -> eyre::Result<()> {
+    let args = Args::parse();
+    let conf = Arc::new(ConnectionConfig::new()?);
+    let session_token = login(&conf)?;
+
+    let (stream_tx, stream_rx) = mpsc::channel();
+    let refresh_conf = conf.clone();
+    let stream_config = SessionConfig {
+        endpoint: StreamEndpoint::from_env()?.proxy(Proxy::configured(&get_config_dir()?)?),
+        refresh_session: Some(Arc::new(move || login(&refresh_conf))),
+        ..Default::default()
+    };
+    let session = StreamSession::connect_with(
+        stream_config.clone(),
+        &conf.app_key,
+        &session_token,
+        stream_tx.clone(),
+    )?;
+    // Warm start from the last run, betfair only sends what changed since
+    let snapshot_path = CacheSnapshot::default_path(SnapshotFormat::Binary)?;
+    let mut snapshot = CacheSnapshot::load_or_default(&snapshot_path).unwrap_or_else(|e| {
+        eprintln!("Warning: starting without the saved caches: {:#}", e);
+        CacheSnapshot::default()
+    });
+    let orders = match snapshot.orders.clocks() {
+        Some((initial_clk, clk)) => OrderSubscriptionMessage::new().resume(initial_clk, clk),
+        None => OrderSubscriptionMessage::new(),
+    };
+    session.subscribe_orders(orders)?;
+    // Tracking data of every race, shown next to the ladder
+    session.subscribe_races()?;
+
+    // Markets get connections of their own, opened when first needed
+    let app_key = conf.app_key.clone();
+    let markets = MarketSubscriptionMessage::new(
+        MarketFilter::new(),
+        MarketDataFilter::new().fields([
+            MarketDataField::ExAllOffers,
+            MarketDataField::ExTraded,
+            MarketDataField::ExTradedVol,
+            MarketDataField::ExLtp,
+            MarketDataField::ExMarketDef,
+        ]),
+    );
+    let events = stream_tx.clone();
+    let subscriptions = SubscriptionManager::new(markets, move || {
+        StreamSession::connect_with(
+            stream_config.clone(),
+            &app_key,
+            &session_token,
+            events.clone(),
+        )
+    })
+    .resume_from(snapshot.markets.clocks())
+    .report_to(stream_tx);
+    // The connections of this run keep clocks of their own
+    snapshot.markets.retain_clocks(|_| false);
+
+    // Setup model
+    let mut model = Model::new(stream_rx, snapshot, subscriptions, args.odds_format);
+    if let Some(market_id) = args.market.as_deref() {
+        model.show_market(market_id);
+    }
 
-    // // Setup terminal
-    // let _ = model.terminal.enter_alternate_screen();
-    // let _ = model.terminal.enable_raw_mode();
+    // Setup terminal
+    let _ = model.terminal.enter_alternate_screen();
+    let _ = model.terminal.enable_raw_mode();
+
+    while !model.quit {
+        match model.app.tick(PollStrategy::Once) {
+            Err(err) => {
+                assert!(model
+                    .app
+                    .attr(
+                        &Id::Status,
+                        Attribute::Title,
+                        AttrValue::Title((
+                            format!("Application error: {}", err),
+                            Alignment::Center
+                        )),
+                    )
+                    .is_ok());
+            }
+            // Handle the Msg sent in app by calling update
+            Ok(messages) if !messages.is_empty() => {
+                model.redraw = true;
+                for msg in messages.into_iter() {
+                    let mut msg = Some(msg);
+                    while msg.is_some() {
+                        // TODO I call update on model but how is update on components called?
+                        msg = model.update(msg);
+                    }
+                }
+            }
+            _ => {}
+        }
+        // Redraw
+        if model.redraw {
+            model.view(); // TODO call view on app how to connect components?
+            model.redraw = false;
+        }
+    }
 
-    // // TODO main loop
-    // while !model.quit {
-    //     match model.app.tick(PollStrategy::Once) {
-    //         Err(err) => {
-    //             assert!(model
-    //                 .app
-    //                 .attr(
-    //                     &Id::Status,
-    //                     Attribute::Title,
-    //                     AttrValue::String(format!("Application error: {}", err)),
-    //                 )
-    //                 .is_ok());
-    //         }
-    //         // Handle the Msg sent in app by calling update
-    //         Ok(messages) if messages.len() > 0 => {
-    //             model.redraw = true;
-    //             for msg in messages.into_iter() {
-    //                 let mut msg = Some(msg);
-    //                 while msg.is_some() {
-    //                     // TODO I call update on model but how is update on components called?
-    //                     msg = model.update(msg);
-    //                 }
-    //             }
-    //         }
-    //         _ => {}
-    //     }
-    //     // Redraw
-    //     if model.redraw {
-    //         model.view(); // TODO call view on app how to connect components?
-    //         model.redraw = false;
-    //     }
-    // }
+    // TODO maybe i need som handle for panics with hooks, is in ratatue manual
+    // Restore terminal
+    let _ = model.terminal.leave_alternate_screen();
+    let _ = model.terminal.disable_raw_mode();
+    let _ = model.terminal.clear_screen();
 
-    // // TODO maybe i need som handle for panics with hooks, is in ratatue manual
-    // // Also might want to add clap for arguments?
-    // // Restore terminal
-    // let _ = model.terminal.leave_alternate_screen();
-    // let _ = model.terminal.disable_raw_mode();
-    // let _ = model.terminal.clear_screen();
+    model.snapshot().save(&snapshot_path)
 }
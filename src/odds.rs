@@ -0,0 +1,387 @@
+// Betfair deals in decimal odds, these turn them into the other ways odds are
+// quoted and back again
+
+use color_eyre::eyre;
+use num::Rational32;
+use std::fmt;
+
+// Fractions bookmakers quote, shortest first. Kept unreduced so 4/6 stays 4/6.
+const FRACTIONS: [(i32, i32); 82] = [
+    (1, 100),
+    (1, 50),
+    (1, 33),
+    (1, 25),
+    (1, 20),
+    (1, 16),
+    (1, 14),
+    (1, 12),
+    (1, 10),
+    (1, 9),
+    (1, 8),
+    (1, 7),
+    (1, 6),
+    (1, 5),
+    (2, 9),
+    (1, 4),
+    (2, 7),
+    (3, 10),
+    (1, 3),
+    (4, 11),
+    (2, 5),
+    (4, 9),
+    (1, 2),
+    (8, 15),
+    (4, 7),
+    (8, 13),
+    (4, 6),
+    (8, 11),
+    (4, 5),
+    (5, 6),
+    (10, 11),
+    (1, 1),
+    (21, 20),
+    (11, 10),
+    (6, 5),
+    (5, 4),
+    (11, 8),
+    (6, 4),
+    (13, 8),
+    (7, 4),
+    (15, 8),
+    (2, 1),
+    (9, 4),
+    (5, 2),
+    (11, 4),
+    (3, 1),
+    (10, 3),
+    (7, 2),
+    (4, 1),
+    (9, 2),
+    (5, 1),
+    (11, 2),
+    (6, 1),
+    (13, 2),
+    (7, 1),
+    (15, 2),
+    (8, 1),
+    (17, 2),
+    (9, 1),
+    (10, 1),
+    (11, 1),
+    (12, 1),
+    (14, 1),
+    (16, 1),
+    (18, 1),
+    (20, 1),
+    (22, 1),
+    (25, 1),
+    (28, 1),
+    (33, 1),
+    (40, 1),
+    (50, 1),
+    (66, 1),
+    (80, 1),
+    (100, 1),
+    (150, 1),
+    (200, 1),
+    (250, 1),
+    (300, 1),
+    (500, 1),
+    (750, 1),
+    (999, 1),
+];
+
+/// How odds are shown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OddsFormat {
+    /// 2.5, what betfair uses
+    #[default]
+    Decimal,
+    /// 6/4, the nearest fraction a bookmaker would quote
+    Fractional,
+    /// +150 or -200, the moneyline
+    American,
+    /// 1.50, the profit on a stake of 1
+    HongKong,
+    /// 0.67 or -0.67, negative when the profit is more than the stake
+    Malay,
+    /// +1.50 or -2.00, negative when the profit is less than the stake
+    Indonesian,
+    /// 40.0%, the chance the odds imply
+    Probability,
+}
+
+impl OddsFormat {
+    /// `decimal` odds written in this format
+    pub fn format(self, decimal: impl Into<f64>) -> String {
+        let decimal = decimal.into();
+        match self {
+            Self::Decimal => decimal.to_string(),
+            Self::Fractional => {
+                let fraction = to_fractional(decimal);
+                format!("{}/{}", fraction.numer(), fraction.denom())
+            }
+            Self::American => format!("{:+.0}", to_american(decimal)),
+            Self::HongKong => format!("{:.2}", to_hong_kong(decimal)),
+            Self::Malay => format!("{:.2}", to_malay(decimal)),
+            Self::Indonesian => format!("{:+.2}", to_indonesian(decimal)),
+            Self::Probability => format!("{:.1}%", implied_probability(decimal) * 100.),
+        }
+    }
+
+    /// Decimal odds from odds written in this format
+    pub fn parse(self, odds: &str) -> eyre::Result<f64> {
+        let number = || {
+            odds.trim()
+                .parse::<f64>()
+                .map_err(|_| eyre::eyre!("{} is not {} odds", odds, self))
+        };
+        match self {
+            Self::Decimal => from_decimal(number()?),
+            Self::Fractional => {
+                let (numer, denom) = odds
+                    .trim()
+                    .split_once('/')
+                    .ok_or_else(|| eyre::eyre!("{} is not fractional odds", odds))?;
+                let parse = |part: &str| {
+                    part.trim()
+                        .parse::<i32>()
+                        .map_err(|_| eyre::eyre!("{} is not fractional odds", odds))
+                };
+                from_fractional(Rational32::new_raw(parse(numer)?, parse(denom)?))
+            }
+            Self::American => from_american(number()?),
+            Self::HongKong => from_hong_kong(number()?),
+            Self::Malay => from_malay(number()?),
+            Self::Indonesian => from_indonesian(number()?),
+            Self::Probability => {
+                let percent = odds.trim().trim_end_matches('%');
+                let percent = percent
+                    .parse::<f64>()
+                    .map_err(|_| eyre::eyre!("{} is not a probability", odds))?;
+                from_probability(percent / 100.)
+            }
+        }
+    }
+}
+
+impl fmt::Display for OddsFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Decimal => "decimal",
+            Self::Fractional => "fractional",
+            Self::American => "american",
+            Self::HongKong => "hong kong",
+            Self::Malay => "malay",
+            Self::Indonesian => "indonesian",
+            Self::Probability => "probability",
+        };
+        f.write_str(name)
+    }
+}
+
+fn from_decimal(decimal: f64) -> eyre::Result<f64> {
+    if decimal > 1. && decimal.is_finite() {
+        Ok(decimal)
+    } else {
+        Err(eyre::eyre!("{} is not decimal odds", decimal))
+    }
+}
+
+/// The common fraction closest to `decimal`, 1/100 and 999/1 at the ends
+pub fn to_fractional(decimal: f64) -> Rational32 {
+    let profit = decimal - 1.;
+    let distance = |&(numer, denom): &(i32, i32)| (numer as f64 / denom as f64 - profit).abs();
+    let (numer, denom) = FRACTIONS
+        .into_iter()
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+        .expect("there are fractions");
+    Rational32::new_raw(numer, denom)
+}
+
+pub fn from_fractional(fraction: Rational32) -> eyre::Result<f64> {
+    if *fraction.numer() <= 0 || *fraction.denom() <= 0 {
+        return Err(eyre::eyre!("{} is not fractional odds", fraction));
+    }
+    Ok(*fraction.numer() as f64 / *fraction.denom() as f64 + 1.)
+}
+
+/// Moneyline, what a stake of 100 wins when positive and what has to be
+/// staked to win 100 when negative
+pub fn to_american(decimal: f64) -> f64 {
+    if decimal >= 2. {
+        (decimal - 1.) * 100.
+    } else {
+        -100. / (decimal - 1.)
+    }
+}
+
+pub fn from_american(american: f64) -> eyre::Result<f64> {
+    if american >= 100. {
+        Ok(american / 100. + 1.)
+    } else if american <= -100. {
+        Ok(100. / -american + 1.)
+    } else {
+        Err(eyre::eyre!("{} is not american odds", american))
+    }
+}
+
+pub fn to_hong_kong(decimal: f64) -> f64 {
+    decimal - 1.
+}
+
+pub fn from_hong_kong(hong_kong: f64) -> eyre::Result<f64> {
+    from_decimal(hong_kong + 1.)
+}
+
+/// Hong Kong odds up to evens, minus one over them above
+pub fn to_malay(decimal: f64) -> f64 {
+    if decimal <= 2. {
+        decimal - 1.
+    } else {
+        -1. / (decimal - 1.)
+    }
+}
+
+pub fn from_malay(malay: f64) -> eyre::Result<f64> {
+    if malay > 0. && malay <= 1. {
+        Ok(malay + 1.)
+    } else if (-1.0..0.).contains(&malay) {
+        Ok(-1. / malay + 1.)
+    } else {
+        Err(eyre::eyre!("{} is not malay odds", malay))
+    }
+}
+
+/// Hong Kong odds from evens up, minus one over them below
+pub fn to_indonesian(decimal: f64) -> f64 {
+    if decimal >= 2. {
+        decimal - 1.
+    } else {
+        -1. / (decimal - 1.)
+    }
+}
+
+pub fn from_indonesian(indonesian: f64) -> eyre::Result<f64> {
+    if indonesian >= 1. {
+        Ok(indonesian + 1.)
+    } else if indonesian <= -1. {
+        Ok(-1. / indonesian + 1.)
+    } else {
+        Err(eyre::eyre!("{} is not indonesian odds", indonesian))
+    }
+}
+
+/// The chance of winning the odds imply, between 0 and 1
+pub fn implied_probability(decimal: f64) -> f64 {
+    1. / decimal
+}
+
+pub fn from_probability(probability: f64) -> eyre::Result<f64> {
+    if probability > 0. && probability < 1. {
+        Ok(1. / probability)
+    } else {
+        Err(eyre::eyre!("{} is not a probability", probability))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::price::{Price, TICKS};
+
+    const FORMATS: [OddsFormat; 7] = [
+        OddsFormat::Decimal,
+        OddsFormat::Fractional,
+        OddsFormat::American,
+        OddsFormat::HongKong,
+        OddsFormat::Malay,
+        OddsFormat::Indonesian,
+        OddsFormat::Probability,
+    ];
+
+    #[test]
+    fn format_odds() {
+        let cases = [
+            (
+                2.5,
+                ["2.5", "6/4", "+150", "1.50", "-0.67", "+1.50", "40.0%"],
+            ),
+            (
+                1.5,
+                ["1.5", "1/2", "-200", "0.50", "0.50", "-2.00", "66.7%"],
+            ),
+            (2., ["2", "1/1", "+100", "1.00", "1.00", "+1.00", "50.0%"]),
+            (
+                1.67,
+                ["1.67", "4/6", "-149", "0.67", "0.67", "-1.49", "59.9%"],
+            ),
+        ];
+        for (decimal, expected) in cases {
+            for (format, expected) in FORMATS.into_iter().zip(expected) {
+                assert_eq!(format.format(decimal), expected, "{} {}", decimal, format);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_common_fraction() {
+        let fraction = |decimal| {
+            let fraction = to_fractional(decimal);
+            (*fraction.numer(), *fraction.denom())
+        };
+        assert_eq!(fraction(1.2), (1, 5));
+        assert_eq!(fraction(3.25), (9, 4));
+        assert_eq!(fraction(1.72), (8, 11));
+        assert_eq!(fraction(1.01), (1, 100));
+        assert_eq!(fraction(1000.), (999, 1));
+        // Off the list snaps to the closest one
+        assert_eq!(fraction(2.95), (2, 1));
+        assert_eq!(fraction(3.3), (9, 4));
+    }
+
+    #[test]
+    fn parse_each_format() {
+        let parsed = |format: OddsFormat, odds| format.parse(odds).unwrap();
+        assert_eq!(parsed(OddsFormat::Decimal, "2.5"), 2.5);
+        assert_eq!(parsed(OddsFormat::Fractional, "6/4"), 2.5);
+        assert_eq!(parsed(OddsFormat::American, "+150"), 2.5);
+        assert_eq!(parsed(OddsFormat::American, "-200"), 1.5);
+        assert_eq!(parsed(OddsFormat::HongKong, "1.5"), 2.5);
+        assert_eq!(parsed(OddsFormat::Malay, "-0.5"), 3.);
+        assert_eq!(parsed(OddsFormat::Malay, "0.5"), 1.5);
+        assert_eq!(parsed(OddsFormat::Indonesian, "-2"), 1.5);
+        assert_eq!(parsed(OddsFormat::Probability, "25%"), 4.);
+
+        for (format, odds) in [
+            (OddsFormat::Decimal, "1"),
+            (OddsFormat::Fractional, "6-4"),
+            (OddsFormat::Fractional, "1/0"),
+            (OddsFormat::American, "50"),
+            (OddsFormat::Malay, "1.5"),
+            (OddsFormat::Indonesian, "0.5"),
+            (OddsFormat::Probability, "0%"),
+            (OddsFormat::HongKong, "evens"),
+        ] {
+            assert!(format.parse(odds).is_err(), "{} {}", format, odds);
+        }
+    }
+
+    #[test]
+    fn round_trip_every_tick() {
+        for tick in 0..TICKS {
+            let decimal = Price::from_tick(tick).unwrap().odds();
+            let close = |back: f64| (back - decimal).abs() < 1e-9;
+            assert!(close(from_american(to_american(decimal)).unwrap()));
+            assert!(close(from_hong_kong(to_hong_kong(decimal)).unwrap()));
+            assert!(close(from_malay(to_malay(decimal)).unwrap()));
+            assert!(close(from_indonesian(to_indonesian(decimal)).unwrap()));
+            assert!(close(
+                from_probability(implied_probability(decimal)).unwrap()
+            ));
+            // Fractions snap, but never further than to the next common one
+            let fraction = from_fractional(to_fractional(decimal)).unwrap();
+            assert_eq!(to_fractional(fraction), to_fractional(decimal));
+        }
+    }
+}
@@ -0,0 +1,299 @@
+// Betfair only accepts prices on its tick table, 350 steps from 1.01 to 1000
+// with the step growing with the odds. A price is kept as its place in the
+// table so it orders and compares exactly, unlike a float.
+
+use color_eyre::eyre;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Number of prices on the table
+pub const TICKS: usize = 350;
+
+// The step in hundredths up to the end of each band
+const BANDS: [(u32, u32); 10] = [
+    (200, 1),
+    (300, 2),
+    (400, 5),
+    (600, 10),
+    (1000, 20),
+    (2000, 50),
+    (3000, 100),
+    (5000, 200),
+    (10000, 500),
+    (100000, 1000),
+];
+
+// Every price in hundredths, lowest first
+const TABLE: [u32; TICKS] = table();
+
+const fn table() -> [u32; TICKS] {
+    let mut table = [0; TICKS];
+    let mut price = 101;
+    let mut tick = 0;
+    let mut band = 0;
+    while tick < TICKS {
+        table[tick] = price;
+        if price >= BANDS[band].0 {
+            band += 1;
+        }
+        // The last tick would step past the table, the loop ends there
+        if band < BANDS.len() {
+            price += BANDS[band].1;
+        }
+        tick += 1;
+    }
+    table
+}
+
+// A float this close to a tick is taken to be on it
+const EPSILON: f64 = 1e-6;
+
+/// Decimal odds on betfair's tick table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(into = "f64", try_from = "f64")]
+pub struct Price(u16);
+
+impl Price {
+    pub const MIN: Price = Price(0);
+    pub const MAX: Price = Price(TICKS as u16 - 1);
+
+    /// The price if `odds` is on the tick table
+    pub fn new(odds: f64) -> eyre::Result<Self> {
+        let hundredths = odds * 100.;
+        let rounded = hundredths.round();
+        if (hundredths - rounded).abs() > EPSILON * 100. {
+            return Err(eyre::eyre!("{} is not a betfair price", odds));
+        }
+        TABLE
+            .binary_search(&(rounded as u32))
+            .map(|tick| Self(tick as u16))
+            .map_err(|_| eyre::eyre!("{} is not a betfair price", odds))
+    }
+
+    /// The price at a place on the table, 0 is 1.01
+    pub fn from_tick(tick: usize) -> Option<Self> {
+        (tick < TICKS).then_some(Self(tick as u16))
+    }
+
+    pub fn tick(self) -> usize {
+        self.0 as usize
+    }
+
+    /// Decimal odds
+    pub fn odds(self) -> f64 {
+        self.hundredths() as f64 / 100.
+    }
+
+    /// Decimal odds in hundredths, exact
+    pub fn hundredths(self) -> u32 {
+        TABLE[self.tick()]
+    }
+
+    /// The lowest price at or above `odds`, a back never asks for less than
+    /// it was given. `None` above 1000.
+    pub fn round_back(odds: f64) -> Option<Self> {
+        if odds.is_nan() {
+            return None;
+        }
+        let tick = TABLE.partition_point(|price| (*price as f64) < odds * 100. - EPSILON);
+        Self::from_tick(tick)
+    }
+
+    /// The highest price at or below `odds`, a lay never risks more than
+    /// it was given. `None` below 1.01.
+    pub fn round_lay(odds: f64) -> Option<Self> {
+        if odds.is_nan() {
+            return None;
+        }
+        let tick = TABLE.partition_point(|price| (*price as f64) <= odds * 100. + EPSILON);
+        tick.checked_sub(1).and_then(Self::from_tick)
+    }
+
+    /// The price closest to `odds`, anything off the table ends up on its
+    /// first or last price. Halfway between two ticks goes to the lower.
+    pub fn nearest(odds: f64) -> Option<Self> {
+        match (Self::round_lay(odds), Self::round_back(odds)) {
+            (Some(lay), Some(back)) if back.odds() - odds < odds - lay.odds() => Some(back),
+            (Some(lay), _) => Some(lay),
+            (None, back) => back,
+        }
+    }
+
+    /// `ticks` steps up the table, or down when negative. `None` when that
+    /// runs off either end.
+    pub fn offset(self, ticks: isize) -> Option<Self> {
+        self.tick()
+            .checked_add_signed(ticks)
+            .and_then(Self::from_tick)
+    }
+
+    /// `ticks` steps up or down the table, stopping at either end
+    pub fn offset_clamped(self, ticks: isize) -> Self {
+        let tick = self.tick().saturating_add_signed(ticks).min(TICKS - 1);
+        Self(tick as u16)
+    }
+
+    pub fn ticks_up(self, ticks: usize) -> Option<Self> {
+        self.tick().checked_add(ticks).and_then(Self::from_tick)
+    }
+
+    pub fn ticks_down(self, ticks: usize) -> Option<Self> {
+        self.tick().checked_sub(ticks).and_then(Self::from_tick)
+    }
+
+    /// Steps from `self` up to `other`, negative when `other` is lower
+    pub fn ticks_to(self, other: Price) -> isize {
+        other.tick() as isize - self.tick() as isize
+    }
+}
+
+impl From<Price> for f64 {
+    fn from(price: Price) -> Self {
+        price.odds()
+    }
+}
+
+impl TryFrom<f64> for Price {
+    type Error = eyre::Report;
+
+    fn try_from(odds: f64) -> eyre::Result<Self> {
+        Self::new(odds)
+    }
+}
+
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.odds())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn tick_table() {
+        assert_eq!(Price::MIN.odds(), 1.01);
+        assert_eq!(Price::MAX.odds(), 1000.);
+        // Where each band starts
+        let starts = [
+            (2.02, 100),
+            (3.05, 150),
+            (4.1, 170),
+            (6.2, 190),
+            (10.5, 210),
+            (21., 230),
+            (32., 240),
+            (55., 250),
+            (110., 260),
+        ];
+        for (odds, tick) in starts {
+            assert_eq!(Price::new(odds).unwrap().tick(), tick, "{}", odds);
+        }
+        assert!(TABLE.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(Price::from_tick(TICKS), None);
+    }
+
+    #[test]
+    fn validate_prices() {
+        assert_eq!(Price::new(1.5).unwrap().to_string(), "1.5");
+        // Float noise is fine, off the table is not
+        assert!(Price::new(0.1 + 0.2 + 2.7).is_ok());
+        for odds in [1., 1.005, 2.01, 3.01, 4.05, 1001., -2., f64::NAN] {
+            assert!(Price::new(odds).is_err(), "{}", odds);
+        }
+    }
+
+    #[test]
+    fn round_toward_back_or_lay() {
+        assert_eq!(Price::round_back(2.01).unwrap().odds(), 2.02);
+        assert_eq!(Price::round_lay(2.01).unwrap().odds(), 2.);
+        assert_eq!(Price::round_back(3.5).unwrap().odds(), 3.5);
+        assert_eq!(Price::round_lay(3.5).unwrap().odds(), 3.5);
+        assert_eq!(Price::round_back(1.), Some(Price::MIN));
+        assert_eq!(Price::round_lay(1.), None);
+        assert_eq!(Price::round_back(1001.), None);
+        assert_eq!(Price::round_lay(1001.), Some(Price::MAX));
+    }
+
+    #[test]
+    fn move_across_bands() {
+        let price = |odds| Price::new(odds).unwrap();
+        assert_eq!(price(1.99).ticks_up(2), Some(price(2.02)));
+        assert_eq!(price(2.02).ticks_down(2), Some(price(1.99)));
+        assert_eq!(price(3.9).offset(3), Some(price(4.1)));
+        assert_eq!(price(95.).offset(2), Some(price(110.)));
+        assert_eq!(price(2.).ticks_to(price(3.)), 50);
+        assert_eq!(price(1000.).ticks_to(price(1.01)), -349);
+
+        assert_eq!(Price::MAX.ticks_up(1), None);
+        assert_eq!(Price::MAX.ticks_up(usize::MAX), None);
+        assert_eq!(Price::MIN.offset(-1), None);
+        assert_eq!(Price::MAX.offset_clamped(5), Price::MAX);
+        assert_eq!(price(1.02).offset_clamped(-5), Price::MIN);
+        assert_eq!(price(1.02).offset_clamped(isize::MAX), Price::MAX);
+    }
+
+    #[test]
+    fn snap_to_nearest() {
+        assert_eq!(Price::nearest(2.013).unwrap().odds(), 2.02);
+        assert_eq!(Price::nearest(2.009).unwrap().odds(), 2.);
+        // Halfway goes down
+        assert_eq!(Price::nearest(2.01).unwrap().odds(), 2.);
+        assert_eq!(Price::nearest(0.5), Some(Price::MIN));
+        assert_eq!(Price::nearest(5000.), Some(Price::MAX));
+        assert_eq!(Price::nearest(f64::NAN), None);
+    }
+
+    #[test]
+    fn every_tick() {
+        let all: Vec<Price> = (0..TICKS)
+            .map(|tick| Price::from_tick(tick).unwrap())
+            .collect();
+        for (tick, &price) in all.iter().enumerate() {
+            // Back and forth through the odds
+            assert_eq!(price.tick(), tick);
+            assert_eq!(Price::new(price.odds()).unwrap(), price);
+            assert_eq!(Price::nearest(price.odds()), Some(price));
+            assert_eq!(Price::round_back(price.odds()), Some(price));
+            assert_eq!(Price::round_lay(price.odds()), Some(price));
+
+            // Just either side of a tick snaps back onto it
+            if let Some(next) = price.ticks_up(1) {
+                let step = next.odds() - price.odds();
+                assert_eq!(Price::nearest(price.odds() + step * 0.4), Some(price));
+                assert_eq!(Price::nearest(next.odds() - step * 0.4), Some(next));
+                assert_eq!(Price::round_back(price.odds() + step * 0.1), Some(next));
+                assert_eq!(Price::round_lay(next.odds() - step * 0.1), Some(price));
+            }
+
+            for (other_tick, &other) in all.iter().enumerate() {
+                let ticks = other_tick as isize - tick as isize;
+                assert_eq!(price.ticks_to(other), ticks);
+                assert_eq!(price.offset(ticks), Some(other));
+                assert_eq!(other.offset(-ticks), Some(price));
+                assert_eq!(price < other, ticks > 0);
+                assert_eq!(price.odds() < other.odds(), ticks > 0);
+            }
+            assert_eq!(price.offset(TICKS as isize - tick as isize), None);
+            assert_eq!(price.offset(-(tick as isize) - 1), None);
+            assert_eq!(price.offset_clamped(TICKS as isize), Price::MAX);
+            assert_eq!(price.offset_clamped(-(TICKS as isize)), Price::MIN);
+        }
+    }
+
+    #[test]
+    fn order_as_map_key() {
+        let mut sizes = BTreeMap::new();
+        for odds in [10., 2.5, 1.01, 100.] {
+            sizes.insert(Price::new(odds).unwrap(), odds);
+        }
+        let ordered: Vec<f64> = sizes.keys().map(|price| price.odds()).collect();
+        assert_eq!(ordered, [1.01, 2.5, 10., 100.]);
+
+        let json = serde_json::to_string(&Price::new(2.5).unwrap()).unwrap();
+        assert_eq!(json, "2.5");
+        assert!(serde_json::from_str::<Price>("2.51").is_err());
+    }
+}
@@ -0,0 +1,175 @@
+// A local stand-in for a corporate proxy used by the tests. It speaks HTTP
+// CONNECT or SOCKS5, records where each client asked to go and relays the bytes.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use super::{Proxy, ProxyKind};
+
+type Targets = Arc<Mutex<Vec<String>>>;
+
+pub struct FakeProxy {
+    kind: ProxyKind,
+    port: u16,
+    credentials: Option<(String, String)>,
+    targets: Targets,
+}
+
+impl FakeProxy {
+    /// With `credentials` the clients have to log in
+    pub fn start(kind: ProxyKind, credentials: Option<(&str, &str)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let credentials =
+            credentials.map(|(user, password)| (String::from(user), String::from(password)));
+        let targets = Targets::default();
+        let required = credentials.clone();
+        let recorded = targets.clone();
+        thread::spawn(move || {
+            for sock in listener.incoming() {
+                let Ok(sock) = sock else { return };
+                let required = required.clone();
+                let recorded = recorded.clone();
+                // Errors just mean the client went away
+                thread::spawn(move || match kind {
+                    ProxyKind::Http => http(sock, required, recorded),
+                    ProxyKind::Socks5 => socks5(sock, required, recorded),
+                });
+            }
+        });
+        Self {
+            kind,
+            port,
+            credentials,
+            targets,
+        }
+    }
+
+    /// How to reach this proxy
+    pub fn proxy(&self) -> Proxy {
+        Proxy {
+            kind: self.kind,
+            host: String::from("127.0.0.1"),
+            port: self.port,
+            credentials: self.credentials.clone(),
+            no_proxy: Vec::new(),
+        }
+    }
+
+    /// Every `host:port` a client asked for
+    pub fn targets(&self) -> Vec<String> {
+        self.targets.lock().unwrap().clone()
+    }
+}
+
+fn http(
+    mut sock: TcpStream,
+    required: Option<(String, String)>,
+    targets: Targets,
+) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        sock.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head).into_owned();
+    let target = head
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("CONNECT "))
+        .and_then(|line| line.split_whitespace().next())
+        .unwrap_or_default()
+        .to_string();
+    if let Some((user, password)) = required {
+        let expected = format!(
+            "Proxy-Authorization: Basic {}",
+            STANDARD.encode(format!("{}:{}", user, password))
+        );
+        if !head.lines().any(|line| line == expected) {
+            return sock.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+        }
+    }
+    targets.lock().unwrap().push(target.clone());
+    let upstream = TcpStream::connect(target)?;
+    sock.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+    relay(sock, upstream)
+}
+
+fn socks5(
+    mut sock: TcpStream,
+    required: Option<(String, String)>,
+    targets: Targets,
+) -> io::Result<()> {
+    let mut greeting = [0u8; 2];
+    sock.read_exact(&mut greeting)?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    sock.read_exact(&mut methods)?;
+    match required {
+        Some((user, password)) => {
+            if !methods.contains(&2) {
+                return sock.write_all(&[5, 0xff]);
+            }
+            sock.write_all(&[5, 2])?;
+            let given_user = read_short(&mut sock, true)?;
+            let given_password = read_short(&mut sock, false)?;
+            if given_user != user.as_bytes() || given_password != password.as_bytes() {
+                return sock.write_all(&[1, 1]);
+            }
+            sock.write_all(&[1, 0])?;
+        }
+        None => sock.write_all(&[5, 0])?,
+    }
+
+    let mut request = [0u8; 4];
+    sock.read_exact(&mut request)?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            sock.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            sock.read_exact(&mut ip)?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => String::from_utf8_lossy(&read_short(&mut sock, false)?).into_owned(),
+    };
+    let mut port = [0u8; 2];
+    sock.read_exact(&mut port)?;
+    let target = format!("{}:{}", host, u16::from_be_bytes(port));
+    targets.lock().unwrap().push(target.clone());
+    let upstream = TcpStream::connect(target)?;
+    sock.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+    relay(sock, upstream)
+}
+
+// A length prefixed string, `versioned` when a version byte comes first
+fn read_short(sock: &mut TcpStream, versioned: bool) -> io::Result<Vec<u8>> {
+    if versioned {
+        sock.read_exact(&mut [0u8; 1])?;
+    }
+    let mut len = [0u8; 1];
+    sock.read_exact(&mut len)?;
+    let mut value = vec![0u8; len[0] as usize];
+    sock.read_exact(&mut value)?;
+    Ok(value)
+}
+
+fn relay(client: TcpStream, upstream: TcpStream) -> io::Result<()> {
+    let mut client_read = client.try_clone()?;
+    let mut upstream_write = upstream.try_clone()?;
+    thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut upstream_write);
+        let _ = upstream_write.shutdown(Shutdown::Write);
+    });
+    let (mut upstream_read, mut client_write) = (upstream, client);
+    io::copy(&mut upstream_read, &mut client_write)?;
+    client_write.shutdown(Shutdown::Write)
+}
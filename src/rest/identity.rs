@@ -5,6 +5,8 @@ use reqwest::{
 };
 use serde::Deserialize;
 
+use crate::{get_config_dir, proxy};
+
 const IDENTITY_URL: &str = "https://identitysso.betfair.se/api";
 
 // // FIX example how to use time in json serde
@@ -15,15 +17,15 @@ const IDENTITY_URL: &str = "https://identitysso.betfair.se/api";
 
 #[derive(Deserialize, Debug)]
 pub struct IdentityResponse {
-    token: String,
-    product: String,
-    status: IdentityStatus,
-    error: IdentityError,
+    pub token: String,
+    pub product: String,
+    pub status: IdentityStatus,
+    pub error: IdentityError,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
-enum IdentityError {
+pub enum IdentityError {
     InputValidationError,
     InternalError,
     NoSession,
@@ -32,14 +34,14 @@ enum IdentityError {
 /// Status enum for logut and keep-alive
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
-enum IdentityStatus {
+pub enum IdentityStatus {
     Success,
     Fail,
 }
 
 /// Keep alive to betfair
 pub fn keep_alive(app_key: &str, token: &str) -> eyre::Result<IdentityResponse> {
-    let client = reqwest::blocking::Client::new();
+    let client = proxy::client_builder(&get_config_dir()?)?.build()?;
     let mut headers = HeaderMap::new();
     headers.insert(ACCEPT, HeaderValue::from_str("application/json").unwrap());
     headers.insert("X-Application", HeaderValue::from_str(app_key).unwrap());
@@ -53,7 +55,7 @@ pub fn keep_alive(app_key: &str, token: &str) -> eyre::Result<IdentityResponse>
 }
 
 pub fn logout(app_key: &str, token: &str) -> eyre::Result<IdentityResponse> {
-    let client = reqwest::blocking::Client::new();
+    let client = proxy::client_builder(&get_config_dir()?)?.build()?;
     let mut headers = HeaderMap::new();
     headers.insert(ACCEPT, HeaderValue::from_str("application/json").unwrap());
     headers.insert("X-Application", HeaderValue::from_str(app_key).unwrap());
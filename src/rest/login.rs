@@ -1,18 +1,18 @@
 use color_eyre::eyre::{self, Context};
 use reqwest::{
-    blocking::Client,
     header::{HeaderMap, HeaderValue, ACCEPT},
     Identity, Method,
 };
-use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs1KeyDer};
 use serde::Deserialize;
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::proxy;
 
 const LOGIN_URL: &str = "https://identitysso-cert.betfair.se/api";
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
-enum LoginStatus {
+pub enum LoginStatus {
     Success,
     AccountAlreadyLocked,
     AccountNowLocked,
@@ -68,7 +68,7 @@ pub fn login(
     app_key: &str,
     username: &str,
     password: &str,
-    mut config_dir: PathBuf,
+    config_dir: PathBuf,
 ) -> eyre::Result<LoginResponse> {
     // Client cert
     let cert = std::fs::read(config_dir.join("betfair-2048.crt"))
@@ -80,7 +80,7 @@ pub fn login(
     let identity = Identity::from_pkcs8_pem(&cert, &key)?;
 
     // Create the client with the identity
-    let client = Client::builder()
+    let client = proxy::client_builder(&config_dir)?
         .identity(identity)
         .danger_accept_invalid_hostnames(true) // Similar to StrictHostnameVerifier but more dangerous
         .build()?;
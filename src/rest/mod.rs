@@ -5,8 +5,6 @@ mod login;
 
 pub use login::*;
 pub use identity::*;
-pub use betting::*;
-pub use account::*;
 
 	// login_url       = "https://identitysso-cert.betfair.se/api/"
 	// identity_url    = "https://identitysso.betfair.se/api/"
@@ -0,0 +1,268 @@
+// The stream client for use inside a tokio runtime. The blocking LinesCodec
+// needs a thread per connection, any number of these share one runtime.
+
+use color_eyre::eyre;
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder, Framed},
+};
+
+use super::{
+    model::{ResponseMessage, StatusResponse},
+    AsyncConnection, AuthenticationMessage, SegmentAssembler, SetId, StreamEndpoint,
+};
+
+/// Frames the stream as one json message per line and numbers the requests
+#[derive(Debug, Default)]
+pub struct MessageCodec {
+    num_msg: usize,
+    // How much of the buffer is known to hold no newline, so a long image
+    // arriving in many reads is only scanned once
+    next_index: usize,
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Id given to the last request encoded
+    pub fn last_id(&self) -> usize {
+        self.num_msg
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = ResponseMessage;
+    type Error = eyre::Report;
+
+    fn decode(&mut self, src: &mut BytesMut) -> eyre::Result<Option<ResponseMessage>> {
+        let Some(offset) = src[self.next_index..].iter().position(|b| *b == b'\n') else {
+            self.next_index = src.len();
+            return Ok(None);
+        };
+        let line = src.split_to(self.next_index + offset + 1);
+        self.next_index = 0;
+        Ok(Some(serde_json::from_slice(line.trim_ascii_end())?))
+    }
+}
+
+impl<T: Serialize + SetId> Encoder<T> for MessageCodec {
+    type Error = eyre::Report;
+
+    fn encode(&mut self, mut message: T, dst: &mut BytesMut) -> eyre::Result<()> {
+        self.num_msg += 1;
+        message.set_id(self.num_msg);
+        dst.extend_from_slice(&serde_json::to_vec(&message)?);
+        dst.extend_from_slice(b"\r\n");
+        Ok(())
+    }
+}
+
+/// An async stream connection. Reading it gives whole messages, segments are
+/// put together first, and any request can be sent into it. Use `split` to
+/// read and write from different tasks.
+pub struct AsyncStreamClient {
+    framed: Framed<Box<dyn AsyncConnection>, MessageCodec>,
+    segments: SegmentAssembler,
+    // Messages read while waiting for a status response
+    pending: VecDeque<ResponseMessage>,
+    connection_id: String,
+}
+
+impl AsyncStreamClient {
+    /// Connect and wait for betfair's connection message
+    pub async fn connect(endpoint: &StreamEndpoint) -> eyre::Result<Self> {
+        let connection = endpoint.open_async().await?;
+        let mut framed = Framed::new(connection, MessageCodec::new());
+        let connection_id = match framed.next().await.transpose()? {
+            Some(ResponseMessage::Connection(connection)) => connection.connection_id,
+            Some(other) => return Err(eyre::eyre!("expected a connection got {:?}", other)),
+            None => return Err(eyre::eyre!("stream closed by betfair")),
+        };
+        Ok(Self {
+            framed,
+            segments: SegmentAssembler::new(),
+            pending: VecDeque::new(),
+            connection_id,
+        })
+    }
+
+    pub fn connection_id(&self) -> &str {
+        &self.connection_id
+    }
+
+    pub async fn authenticate(
+        &mut self,
+        authentication: AuthenticationMessage,
+    ) -> eyre::Result<StatusResponse> {
+        self.request(authentication).await
+    }
+
+    /// Send a request and wait for the status answering it. Anything else
+    /// arriving meanwhile is kept for the next read.
+    pub async fn request<T: Serialize + SetId>(
+        &mut self,
+        message: T,
+    ) -> eyre::Result<StatusResponse> {
+        self.framed.send(message).await?;
+        let id = self.framed.codec().last_id();
+        loop {
+            match self.framed.next().await.transpose()? {
+                Some(ResponseMessage::Status(status))
+                    if status.id.is_none_or(|status_id| status_id == id) =>
+                {
+                    return Ok(status)
+                }
+                Some(other) => self.pending.push_back(other),
+                None => return Err(eyre::eyre!("stream closed by betfair")),
+            }
+        }
+    }
+}
+
+impl Stream for AsyncStreamClient {
+    type Item = eyre::Result<ResponseMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            let message = match this.pending.pop_front() {
+                Some(message) => message,
+                None => match ready!(this.framed.poll_next_unpin(cx)) {
+                    Some(Ok(message)) => message,
+                    other => return Poll::Ready(other),
+                },
+            };
+            if let Some(message) = this.segments.push(message) {
+                return Poll::Ready(Some(Ok(message)));
+            }
+        }
+    }
+}
+
+impl<T: Serialize + SetId> Sink<T> for AsyncStreamClient {
+    type Error = eyre::Report;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<eyre::Result<()>> {
+        Sink::<T>::poll_ready(Pin::new(&mut self.framed), cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: T) -> eyre::Result<()> {
+        Pin::new(&mut self.framed).start_send(message)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<eyre::Result<()>> {
+        Sink::<T>::poll_flush(Pin::new(&mut self.framed), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<eyre::Result<()>> {
+        Sink::<T>::poll_close(Pin::new(&mut self.framed), cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::{
+        fake_server::{FakeServer, Reply},
+        model::{ChangeType, StatusCode},
+        HeartbeatMessage, MarketDataFilter, MarketFilter, MarketSubscriptionMessage,
+        RequestMessage,
+    };
+    use serde_json::Value;
+
+    #[test]
+    fn decode_lines_split_across_reads() {
+        let heartbeat =
+            br#"{"op":"status","id":1,"statusCode":"SUCCESS","connectionClosed":false}"#;
+        let mut sut = MessageCodec::new();
+        let mut buffer = BytesMut::new();
+        for chunk in heartbeat.chunks(10) {
+            buffer.extend_from_slice(chunk);
+            assert!(sut.decode(&mut buffer).unwrap().is_none());
+            // What was scanned is not scanned again
+            assert_eq!(sut.next_index, buffer.len());
+        }
+        buffer.extend_from_slice(b"\r\n");
+        buffer.extend_from_slice(heartbeat);
+        buffer.extend_from_slice(b"\r\n");
+        for _ in 0..2 {
+            let Some(ResponseMessage::Status(status)) = sut.decode(&mut buffer).unwrap() else {
+                panic!("expected a status");
+            };
+            assert_eq!(status.id, Some(1));
+        }
+        assert!(buffer.is_empty());
+        assert!(sut.decode(&mut buffer).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn subscribe_and_read_whole_messages() {
+        let server = FakeServer::start(|_, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            match request["op"].as_str().unwrap() {
+                "marketSubscription" => Reply::send([
+                    FakeServer::status_ok(id),
+                    format!(
+                        r#"{{"op":"mcm","id":{},"initialClk":"i","pt":1,"ct":"SUB_IMAGE","segmentType":"SEG_START","mc":[{{"id":"1.1","img":true}}]}}"#,
+                        id
+                    ),
+                    format!(
+                        r#"{{"op":"mcm","id":{},"clk":"c","pt":2,"segmentType":"SEG_END","mc":[{{"id":"1.2","img":true}}]}}"#,
+                        id
+                    ),
+                ]),
+                _ => Reply::send([FakeServer::status_ok(id)]),
+            }
+        });
+
+        let mut client = AsyncStreamClient::connect(&server.endpoint())
+            .await
+            .unwrap();
+        assert_eq!(client.connection_id(), "fake-0");
+        let status = client
+            .authenticate(AuthenticationMessage::new("key", "token"))
+            .await
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::Success);
+
+        // Requests of different kinds through one split sink
+        let (mut requests, mut messages) = client.split::<RequestMessage>();
+        requests
+            .send(
+                MarketSubscriptionMessage::new(MarketFilter::new(), MarketDataFilter::new()).into(),
+            )
+            .await
+            .unwrap();
+        requests.send(HeartbeatMessage::new().into()).await.unwrap();
+
+        let Some(Ok(ResponseMessage::Status(status))) = messages.next().await else {
+            panic!("expected the subscription status");
+        };
+        assert_eq!(status.id, Some(2));
+        let Some(Ok(ResponseMessage::Mcm(mcm))) = messages.next().await else {
+            panic!("expected an assembled mcm");
+        };
+        assert_eq!(mcm.ct, Some(ChangeType::SubImage));
+        assert_eq!(mcm.mc.unwrap().len(), 2);
+        let Some(Ok(ResponseMessage::Status(status))) = messages.next().await else {
+            panic!("expected the heartbeat status");
+        };
+        assert_eq!(status.id, Some(3));
+
+        let ops: Vec<Value> = server
+            .requests()
+            .into_iter()
+            .map(|(_, request)| request["op"].clone())
+            .collect();
+        assert_eq!(ops, ["authentication", "marketSubscription", "heartbeat"]);
+    }
+}
@@ -0,0 +1,299 @@
+use color_eyre::eyre::{self, WrapErr};
+use rustls::{ClientConfig, ClientConnection, RootCertStore};
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsConnector;
+
+use crate::proxy::Proxy;
+
+const PRODUCTION_HOST: &str = "stream-api.betfair.com";
+const INTEGRATION_HOST: &str = "stream-api-integration.betfair.com";
+const PORT: u16 = 443;
+
+/// A connected socket, encrypted or not
+pub trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// An async connected socket, encrypted or not
+pub trait AsyncConnection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncConnection for T {}
+
+/// How the stream connection is secured
+#[derive(Debug, Clone)]
+pub enum Transport {
+    /// TLS trusting these roots
+    Tls(RootCertStore),
+    /// No encryption, only meant for a local fake server
+    Plain,
+}
+
+/// Where to connect the stream and how
+#[derive(Debug, Clone)]
+pub struct StreamEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub transport: Transport,
+    /// Tunnel the connection through this proxy
+    pub proxy: Option<Proxy>,
+}
+
+impl StreamEndpoint {
+    /// TLS to any host, trusting the public roots
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: String::from(host),
+            port,
+            transport: Transport::Tls(RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+            }),
+            proxy: None,
+        }
+    }
+
+    pub fn production() -> Self {
+        Self::new(PRODUCTION_HOST, PORT)
+    }
+
+    /// Betfair's integration environment
+    pub fn integration() -> Self {
+        Self::new(INTEGRATION_HOST, PORT)
+    }
+
+    /// Plain tcp, for a mock running locally
+    pub fn plain(host: &str, port: u16) -> Self {
+        Self {
+            host: String::from(host),
+            port,
+            transport: Transport::Plain,
+            proxy: None,
+        }
+    }
+
+    pub fn proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Trust only the certificates in a PEM bundle instead of the public roots
+    pub fn ca_bundle(mut self, path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let pem = std::fs::read(path)
+            .wrap_err_with(|| format!("unable to read ca bundle {}", path.display()))?;
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+            roots.add(cert?)?;
+        }
+        if roots.is_empty() {
+            return Err(eyre::eyre!("no certificates in {}", path.display()));
+        }
+        self.transport = Transport::Tls(roots);
+        Ok(self)
+    }
+
+    /// Production unless the env says otherwise:
+    /// BFG_STREAM_ENV production or integration,
+    /// BFG_STREAM_HOST and BFG_STREAM_PORT to point somewhere else,
+    /// BFG_STREAM_CA a PEM bundle to trust instead of the public roots,
+    /// BFG_STREAM_PLAIN set to 1 for plain tcp.
+    pub fn from_env() -> eyre::Result<Self> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> eyre::Result<Self> {
+        let mut endpoint = match var("BFG_STREAM_ENV").as_deref() {
+            None | Some("production") => Self::production(),
+            Some("integration") => Self::integration(),
+            Some(other) => return Err(eyre::eyre!("unknown BFG_STREAM_ENV {}", other)),
+        };
+        if let Some(host) = var("BFG_STREAM_HOST") {
+            endpoint.host = host;
+        }
+        if let Some(port) = var("BFG_STREAM_PORT") {
+            endpoint.port = port.parse().wrap_err("BFG_STREAM_PORT is not a port")?;
+        }
+        if let Some(ca) = var("BFG_STREAM_CA") {
+            endpoint = endpoint.ca_bundle(ca)?;
+        }
+        if var("BFG_STREAM_PLAIN").is_some_and(|plain| plain == "1") {
+            endpoint.transport = Transport::Plain;
+        }
+        Ok(endpoint)
+    }
+
+    /// Connect, the returned socket is the same one the connection runs on
+    pub fn open(&self) -> eyre::Result<(Box<dyn Connection>, TcpStream)> {
+        let sock = match self
+            .proxy
+            .as_ref()
+            .filter(|proxy| !proxy.bypasses(&self.host))
+        {
+            Some(proxy) => proxy.connect(&self.host, self.port)?,
+            None => TcpStream::connect((self.host.as_str(), self.port))?,
+        };
+        let handle = sock.try_clone()?;
+        let connection: Box<dyn Connection> = match &self.transport {
+            Transport::Tls(roots) => {
+                let config = ClientConfig::builder()
+                    .with_root_certificates(roots.clone())
+                    .with_no_client_auth();
+                let server_name = self.host.clone().try_into()?;
+                let conn = ClientConnection::new(Arc::new(config), server_name)?;
+                Box::new(rustls::StreamOwned::new(conn, sock))
+            }
+            Transport::Plain => Box::new(sock),
+        };
+        Ok((connection, handle))
+    }
+
+    pub async fn open_async(&self) -> eyre::Result<Box<dyn AsyncConnection>> {
+        let sock = match self
+            .proxy
+            .clone()
+            .filter(|proxy| !proxy.bypasses(&self.host))
+        {
+            // The tunnel is set up blocking, the socket is async from then on
+            Some(proxy) => {
+                let (host, port) = (self.host.clone(), self.port);
+                let sock =
+                    tokio::task::spawn_blocking(move || proxy.connect(&host, port)).await??;
+                sock.set_nonblocking(true)?;
+                tokio::net::TcpStream::from_std(sock)?
+            }
+            None => tokio::net::TcpStream::connect((self.host.as_str(), self.port)).await?,
+        };
+        Ok(match &self.transport {
+            Transport::Tls(roots) => {
+                let config = ClientConfig::builder()
+                    .with_root_certificates(roots.clone())
+                    .with_no_client_auth();
+                let server_name = self.host.clone().try_into()?;
+                let tls = TlsConnector::from(Arc::new(config))
+                    .connect(server_name, sock)
+                    .await?;
+                Box::new(tls)
+            }
+            Transport::Plain => Box::new(sock),
+        })
+    }
+}
+
+impl Default for StreamEndpoint {
+    fn default() -> Self {
+        Self::production()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proxy::{fake_proxy::FakeProxy, ProxyKind},
+        stream::{
+            fake_server::{FakeServer, Reply},
+            model::ResponseMessage,
+            AsyncStreamClient, AuthenticationMessage, LinesCodec,
+        },
+    };
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    fn answer_everything(_: usize, request: &Value) -> Reply {
+        Reply::send([FakeServer::status_ok(request["id"].as_u64().unwrap())])
+    }
+
+    fn from(vars: &[(&str, &str)]) -> eyre::Result<StreamEndpoint> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        StreamEndpoint::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn endpoint_from_env() {
+        let production = from(&[]).unwrap();
+        assert_eq!(production.host, PRODUCTION_HOST);
+        assert!(matches!(production.transport, Transport::Tls(_)));
+
+        let integration = from(&[("BFG_STREAM_ENV", "integration")]).unwrap();
+        assert_eq!(integration.host, INTEGRATION_HOST);
+        assert_eq!(integration.port, 443);
+
+        let mock = from(&[
+            ("BFG_STREAM_HOST", "localhost"),
+            ("BFG_STREAM_PORT", "8443"),
+            ("BFG_STREAM_PLAIN", "1"),
+        ])
+        .unwrap();
+        assert_eq!((mock.host.as_str(), mock.port), ("localhost", 8443));
+        assert!(matches!(mock.transport, Transport::Plain));
+
+        assert!(from(&[("BFG_STREAM_ENV", "staging")]).is_err());
+        assert!(from(&[("BFG_STREAM_PORT", "https")]).is_err());
+        assert!(from(&[("BFG_STREAM_CA", "/does/not/exist.pem")]).is_err());
+    }
+
+    #[test]
+    fn stream_through_proxy() {
+        for kind in [ProxyKind::Http, ProxyKind::Socks5] {
+            let server = FakeServer::start(answer_everything);
+            let fake = FakeProxy::start(kind, Some(("trader", "secret")));
+            let endpoint = server.endpoint().proxy(Some(fake.proxy()));
+
+            let mut codec = LinesCodec::connect(&endpoint).unwrap();
+            assert!(matches!(
+                codec.read_message().unwrap(),
+                ResponseMessage::Connection(_)
+            ));
+            let status = codec
+                .authenticate(AuthenticationMessage::new("key", "token"))
+                .unwrap();
+            assert_eq!(status.id, Some(1));
+            assert_eq!(fake.targets(), [format!("localhost:{}", endpoint.port)]);
+        }
+    }
+
+    #[test]
+    fn no_proxy_connects_directly() {
+        let server = FakeServer::start(answer_everything);
+        let fake = FakeProxy::start(ProxyKind::Http, None);
+        let endpoint = server
+            .endpoint()
+            .proxy(Some(fake.proxy().no_proxy("localhost")));
+        let mut codec = LinesCodec::connect(&endpoint).unwrap();
+        assert!(matches!(
+            codec.read_message().unwrap(),
+            ResponseMessage::Connection(_)
+        ));
+        assert!(fake.targets().is_empty());
+    }
+
+    #[test]
+    fn proxy_rejects_wrong_credentials() {
+        for kind in [ProxyKind::Http, ProxyKind::Socks5] {
+            let server = FakeServer::start(answer_everything);
+            let fake = FakeProxy::start(kind, Some(("trader", "secret")));
+            let mut proxy = fake.proxy();
+            proxy.credentials = Some((String::from("trader"), String::from("guess")));
+            assert!(LinesCodec::connect(&server.endpoint().proxy(Some(proxy))).is_err());
+            assert!(fake.targets().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn async_stream_through_proxy() {
+        let server = FakeServer::start(answer_everything);
+        let fake = FakeProxy::start(ProxyKind::Socks5, None);
+        let endpoint = server.endpoint().proxy(Some(fake.proxy()));
+        let client = AsyncStreamClient::connect(&endpoint).await.unwrap();
+        assert_eq!(client.connection_id(), "fake-0");
+        assert_eq!(fake.targets(), [format!("localhost:{}", endpoint.port)]);
+    }
+}
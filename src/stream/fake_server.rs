@@ -0,0 +1,234 @@
+// A local stand-in for the betfair stream used by the tests. It speaks TLS with
+// a throw-away certificate, or plain tcp, answers every request line through a
+// script and records what it was sent.
+
+use rustls::{pki_types::PrivatePkcs8KeyDer, RootCertStore, ServerConfig, ServerConnection};
+use serde_json::Value;
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use super::{StreamEndpoint, Transport};
+
+/// What the server does after a request
+pub struct Reply {
+    frames: Vec<String>,
+    close: bool,
+}
+
+impl Reply {
+    pub fn send(frames: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            frames: frames.into_iter().collect(),
+            close: false,
+        }
+    }
+
+    /// Send the frames then drop the connection
+    pub fn close(frames: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            frames: frames.into_iter().collect(),
+            close: true,
+        }
+    }
+}
+
+type Requests = Arc<Mutex<Vec<(usize, Value)>>>;
+
+pub struct FakeServer {
+    port: u16,
+    // The certificate served, None for plain tcp
+    cert: Option<rcgen::CertifiedKey>,
+    requests: Requests,
+}
+
+impl FakeServer {
+    /// `script` gets the connection number, counted from 0, and the request
+    pub fn start<F>(script: F) -> Self
+    where
+        F: FnMut(usize, &Value) -> Reply + Send + 'static,
+    {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.cert.der().clone()],
+                PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()).into(),
+            )
+            .unwrap();
+        Self::listen(Some(Arc::new(config)), Some(cert), script)
+    }
+
+    /// Like `start` without TLS
+    pub fn start_plain<F>(script: F) -> Self
+    where
+        F: FnMut(usize, &Value) -> Reply + Send + 'static,
+    {
+        Self::listen(None, None, script)
+    }
+
+    fn listen<F>(
+        config: Option<Arc<ServerConfig>>,
+        cert: Option<rcgen::CertifiedKey>,
+        script: F,
+    ) -> Self
+    where
+        F: FnMut(usize, &Value) -> Reply + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Requests::default();
+        let recorded = requests.clone();
+        thread::spawn(move || serve(listener, config, script, recorded));
+        Self {
+            port,
+            cert,
+            requests,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn endpoint(&self) -> StreamEndpoint {
+        let transport = match self.cert.as_ref() {
+            Some(cert) => {
+                let mut roots = RootCertStore::empty();
+                roots.add(cert.cert.der().clone()).unwrap();
+                Transport::Tls(roots)
+            }
+            None => Transport::Plain,
+        };
+        StreamEndpoint {
+            host: String::from("localhost"),
+            port: self.port,
+            transport,
+            proxy: None,
+        }
+    }
+
+    /// The server certificate as PEM
+    pub fn ca_pem(&self) -> String {
+        self.cert.as_ref().expect("plain server").cert.pem()
+    }
+
+    /// Every request received so far with the connection it came in on
+    pub fn requests(&self) -> Vec<(usize, Value)> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn status_ok(id: u64) -> String {
+        format!(
+            r#"{{"op":"status","id":{},"statusCode":"SUCCESS","connectionClosed":false}}"#,
+            id
+        )
+    }
+}
+
+fn serve<F>(
+    listener: TcpListener,
+    config: Option<Arc<ServerConfig>>,
+    mut script: F,
+    requests: Requests,
+) where
+    F: FnMut(usize, &Value) -> Reply,
+{
+    for (connection, sock) in listener.incoming().enumerate() {
+        let Ok(sock) = sock else { return };
+        // Errors just mean the client went away, wait for the next one
+        let _ = handle(connection, sock, &config, &mut script, &requests);
+    }
+}
+
+// One accepted client
+enum Client {
+    Tls(Box<rustls::StreamOwned<ServerConnection, TcpStream>>),
+    Plain(TcpStream),
+}
+
+impl Client {
+    fn close(&mut self) -> io::Result<()> {
+        if let Self::Tls(tls) = self {
+            tls.conn.send_close_notify();
+            tls.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for Client {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tls(tls) => tls.read(buf),
+            Self::Plain(sock) => sock.read(buf),
+        }
+    }
+}
+
+impl Write for Client {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tls(tls) => tls.write(buf),
+            Self::Plain(sock) => sock.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tls(tls) => tls.flush(),
+            Self::Plain(sock) => sock.flush(),
+        }
+    }
+}
+
+fn handle<F>(
+    connection: usize,
+    sock: TcpStream,
+    config: &Option<Arc<ServerConfig>>,
+    script: &mut F,
+    requests: &Requests,
+) -> io::Result<()>
+where
+    F: FnMut(usize, &Value) -> Reply,
+{
+    let client = match config {
+        Some(config) => {
+            let conn = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+            Client::Tls(Box::new(rustls::StreamOwned::new(conn, sock)))
+        }
+        None => Client::Plain(sock),
+    };
+    let mut stream = BufReader::new(client);
+    write_frame(
+        stream.get_mut(),
+        &format!(
+            r#"{{"op":"connection","connectionId":"fake-{}"}}"#,
+            connection
+        ),
+    )?;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let request: Value = serde_json::from_str(line.trim_end())?;
+        requests.lock().unwrap().push((connection, request.clone()));
+        let reply = script(connection, &request);
+        for frame in reply.frames.iter() {
+            write_frame(stream.get_mut(), frame)?;
+        }
+        if reply.close {
+            return stream.get_mut().close();
+        }
+    }
+}
+
+fn write_frame(stream: &mut impl Write, frame: &str) -> io::Result<()> {
+    stream.write_all(frame.as_bytes())?;
+    stream.write_all(b"\r\n")?;
+    stream.flush()
+}
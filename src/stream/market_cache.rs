@@ -0,0 +1,777 @@
+// The state of every subscribed market, built from the images and deltas of
+// the market stream. Sizes in a delta replace what was there, a size of 0
+// takes the price off the ladder. Changes to a market definition are also
+// told apart as events, the definition itself is always sent whole.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use super::model::{
+    ChangeType, LevelPriceSize, MarketChange, MarketChangeMessage, MarketDefinition, MarketStatus,
+    PriceSize, RunnerChange, RunnerStatus,
+};
+
+/// Where a subscription is at, to resume it from
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Clocks {
+    // The request that started the subscription
+    id: Option<usize>,
+    initial_clk: Option<String>,
+    clk: Option<String>,
+}
+
+impl Clocks {
+    /// Take the clocks of a frame. A subscription starts with an image or a
+    /// resumed delta, frames with another id after that are still in flight
+    /// from the subscription it replaced and are left out.
+    pub fn update(
+        &mut self,
+        id: Option<usize>,
+        ct: Option<ChangeType>,
+        initial_clk: &Option<String>,
+        clk: &Option<String>,
+    ) {
+        let starts = initial_clk.is_some()
+            || matches!(ct, Some(ChangeType::SubImage | ChangeType::ResubDelta));
+        if starts {
+            self.id = id;
+        } else if id != self.id {
+            return;
+        }
+        if initial_clk.is_some() {
+            self.initial_clk.clone_from(initial_clk);
+        }
+        if clk.is_some() {
+            self.clk.clone_from(clk);
+        }
+    }
+
+    pub fn get(&self) -> Option<(&str, &str)> {
+        Some((self.initial_clk.as_deref()?, self.clk.as_deref()?))
+    }
+}
+
+/// A runner is known by its selection id and handicap, the handicap is 0
+/// except on handicap markets where one selection has several lines
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunnerKey {
+    pub selection_id: i64,
+    pub handicap: f64,
+}
+
+impl RunnerKey {
+    pub fn new(selection_id: i64, handicap: f64) -> Self {
+        Self {
+            selection_id,
+            handicap,
+        }
+    }
+}
+
+impl PartialEq for RunnerKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RunnerKey {}
+
+impl PartialOrd for RunnerKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RunnerKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.selection_id
+            .cmp(&other.selection_id)
+            .then(self.handicap.total_cmp(&other.handicap))
+    }
+}
+
+impl Hash for RunnerKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.selection_id.hash(state);
+        self.handicap.to_bits().hash(state);
+    }
+}
+
+// Maps keyed by runner are saved as a list of pairs, json only has string keys
+pub(super) mod by_runner {
+    use super::*;
+
+    pub fn serialize<S: Serializer, V: Serialize>(
+        map: &BTreeMap<RunnerKey, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<RunnerKey, V>, D::Error> {
+        let pairs: Vec<(RunnerKey, V)> = Deserialize::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+/// Sizes by price, lowest price first
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PriceLadder {
+    levels: Vec<PriceSize>,
+}
+
+impl PriceLadder {
+    pub fn update(&mut self, changes: &[PriceSize]) {
+        for &[price, size] in changes {
+            let found = self
+                .levels
+                .binary_search_by(|[level, _]| level.total_cmp(&price));
+            match (found, size == 0.) {
+                (Ok(index), true) => {
+                    self.levels.remove(index);
+                }
+                (Ok(index), false) => self.levels[index][1] = size,
+                (Err(_), true) => {}
+                (Err(index), false) => self.levels.insert(index, [price, size]),
+            }
+        }
+    }
+
+    pub fn levels(&self) -> &[PriceSize] {
+        &self.levels
+    }
+
+    pub fn size_at(&self, price: f64) -> Option<f64> {
+        self.levels
+            .binary_search_by(|[level, _]| level.total_cmp(&price))
+            .ok()
+            .map(|index| self.levels[index][1])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+}
+
+/// Price and size by position, best first
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct LevelLadder {
+    levels: BTreeMap<u32, PriceSize>,
+}
+
+impl LevelLadder {
+    pub fn update(&mut self, changes: &[LevelPriceSize]) {
+        for &[level, price, size] in changes {
+            if size == 0. {
+                self.levels.remove(&(level as u32));
+            } else {
+                self.levels.insert(level as u32, [price, size]);
+            }
+        }
+    }
+
+    /// The price and size at each level, best first
+    pub fn levels(&self) -> impl Iterator<Item = &PriceSize> {
+        self.levels.values()
+    }
+
+    /// The best price and size
+    pub fn best(&self) -> Option<&PriceSize> {
+        self.levels.get(&0)
+    }
+}
+
+/// Everything the stream tells about one runner
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunnerBook {
+    /// Available to back
+    pub atb: PriceLadder,
+    /// Available to lay
+    pub atl: PriceLadder,
+    /// Best available to back
+    pub batb: LevelLadder,
+    /// Best available to lay
+    pub batl: LevelLadder,
+    /// Best display available to back, virtual prices included
+    pub bdatb: LevelLadder,
+    /// Best display available to lay, virtual prices included
+    pub bdatl: LevelLadder,
+    /// Traded volume by price
+    pub trd: PriceLadder,
+    /// Last traded price
+    pub ltp: Option<f64>,
+    /// Total traded volume
+    pub tv: Option<f64>,
+    /// Starting price near
+    pub spn: Option<f64>,
+    /// Starting price far
+    pub spf: Option<f64>,
+    /// Starting price back
+    pub spb: PriceLadder,
+    /// Starting price lay
+    pub spl: PriceLadder,
+}
+
+impl RunnerBook {
+    fn update(&mut self, change: &RunnerChange) {
+        let ladders = [
+            (&mut self.atb, &change.atb),
+            (&mut self.atl, &change.atl),
+            (&mut self.trd, &change.trd),
+            (&mut self.spb, &change.spb),
+            (&mut self.spl, &change.spl),
+        ];
+        for (ladder, changes) in ladders {
+            if let Some(changes) = changes {
+                ladder.update(changes);
+            }
+        }
+        let ladders = [
+            (&mut self.batb, &change.batb),
+            (&mut self.batl, &change.batl),
+            (&mut self.bdatb, &change.bdatb),
+            (&mut self.bdatl, &change.bdatl),
+        ];
+        for (ladder, changes) in ladders {
+            if let Some(changes) = changes {
+                ladder.update(changes);
+            }
+        }
+        let values = [
+            (&mut self.ltp, change.ltp),
+            (&mut self.tv, change.tv),
+            (&mut self.spn, change.spn),
+            (&mut self.spf, change.spf),
+        ];
+        for (value, changed) in values {
+            if changed.is_some() {
+                *value = changed;
+            }
+        }
+    }
+}
+
+/// One market as last seen on the stream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketBook {
+    pub market_id: String,
+    /// Only sent in the image and when something in it changes
+    pub definition: Option<MarketDefinition>,
+    /// Total amount matched
+    pub tv: Option<f64>,
+    #[serde(with = "by_runner")]
+    pub runners: BTreeMap<RunnerKey, RunnerBook>,
+    /// Publish time of the last change in milliseconds since epoch
+    pub pt: i64,
+}
+
+impl MarketBook {
+    pub fn new(market_id: &str) -> Self {
+        Self {
+            market_id: String::from(market_id),
+            definition: None,
+            tv: None,
+            runners: BTreeMap::new(),
+            pt: 0,
+        }
+    }
+
+    pub fn runner(&self, selection_id: i64, handicap: f64) -> Option<&RunnerBook> {
+        self.runners.get(&RunnerKey::new(selection_id, handicap))
+    }
+
+    fn update(&mut self, change: &MarketChange, pt: i64) {
+        self.pt = pt;
+        if let Some(definition) = change.market_definition.as_ref() {
+            self.definition = Some(definition.clone());
+        }
+        if change.tv.is_some() {
+            self.tv = change.tv;
+        }
+        for runner in change.rc.iter().flatten() {
+            self.runners
+                .entry(RunnerKey::new(runner.id, runner.hc.unwrap_or_default()))
+                .or_default()
+                .update(runner);
+        }
+    }
+}
+
+/// Something in a market definition changed that traders react to
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarketEvent {
+    /// Like OPEN to SUSPENDED, or SUSPENDED to CLOSED
+    Status {
+        market_id: String,
+        from: MarketStatus,
+        to: MarketStatus,
+    },
+    InPlay {
+        market_id: String,
+        in_play: bool,
+    },
+    /// Seconds a bet waits before it is matched in play
+    BetDelay {
+        market_id: String,
+        from: u32,
+        to: u32,
+    },
+    /// A runner was removed, or settled as a winner or loser
+    RunnerStatus {
+        market_id: String,
+        runner: RunnerKey,
+        from: RunnerStatus,
+        to: RunnerStatus,
+    },
+    /// The reduction applied to matched bets when a runner is removed
+    AdjustmentFactor {
+        market_id: String,
+        runner: RunnerKey,
+        from: Option<f64>,
+        to: Option<f64>,
+    },
+}
+
+impl MarketEvent {
+    // Everything that differs between two versions of a definition
+    fn between(market_id: &str, from: &MarketDefinition, to: &MarketDefinition) -> Vec<Self> {
+        let market_id = String::from(market_id);
+        let mut events = Vec::new();
+        if from.status != to.status {
+            events.push(Self::Status {
+                market_id: market_id.clone(),
+                from: from.status,
+                to: to.status,
+            });
+        }
+        if from.in_play != to.in_play {
+            events.push(Self::InPlay {
+                market_id: market_id.clone(),
+                in_play: to.in_play,
+            });
+        }
+        if from.bet_delay != to.bet_delay {
+            events.push(Self::BetDelay {
+                market_id: market_id.clone(),
+                from: from.bet_delay,
+                to: to.bet_delay,
+            });
+        }
+        for runner in &to.runners {
+            let key = RunnerKey::new(runner.id, runner.hc.unwrap_or_default());
+            let Some(before) = from
+                .runners
+                .iter()
+                .find(|before| RunnerKey::new(before.id, before.hc.unwrap_or_default()) == key)
+            else {
+                continue;
+            };
+            if before.status != runner.status {
+                events.push(Self::RunnerStatus {
+                    market_id: market_id.clone(),
+                    runner: key,
+                    from: before.status,
+                    to: runner.status,
+                });
+            }
+            if before.adjustment_factor != runner.adjustment_factor {
+                events.push(Self::AdjustmentFactor {
+                    market_id: market_id.clone(),
+                    runner: key,
+                    from: before.adjustment_factor,
+                    to: runner.adjustment_factor,
+                });
+            }
+        }
+        events
+    }
+}
+
+/// Every market on the stream by market id. Markets may come in on several
+/// connections, each resuming from the clocks of its own subscription.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketCache {
+    markets: HashMap<String, MarketBook>,
+    // Where each connection feeding the cache is at, by connection
+    clocks: BTreeMap<usize, Clocks>,
+}
+
+impl MarketCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Apply a whole message read on `connection`, segments have to be put
+    /// together first. Returns what changed in the market definitions.
+    pub fn update(&mut self, connection: usize, mcm: &MarketChangeMessage) -> Vec<MarketEvent> {
+        self.clocks.entry(connection).or_default().update(
+            mcm.id,
+            mcm.ct,
+            &mcm.initial_clk,
+            &mcm.clk,
+        );
+        let mut events = Vec::new();
+        for change in mcm.mc.iter().flatten() {
+            // An image replaces whatever we had for the market, the old
+            // definition is still compared against
+            let previous = match change.img {
+                Some(true) => self
+                    .markets
+                    .insert(change.id.clone(), MarketBook::new(&change.id))
+                    .and_then(|market| market.definition),
+                _ if change.market_definition.is_some() => self
+                    .markets
+                    .get(&change.id)
+                    .and_then(|market| market.definition.clone()),
+                _ => None,
+            };
+            self.markets
+                .entry(change.id.clone())
+                .or_insert_with(|| MarketBook::new(&change.id))
+                .update(change, mcm.pt);
+            if let (Some(from), Some(to)) = (previous, change.market_definition.as_ref()) {
+                events.extend(MarketEvent::between(&change.id, &from, to));
+            }
+        }
+        events
+    }
+
+    pub fn market(&self, market_id: &str) -> Option<&MarketBook> {
+        self.markets.get(market_id)
+    }
+
+    /// The initial clk and clk of every connection in the order they were
+    /// opened, to resume their subscriptions from where the cache is
+    pub fn clocks(&self) -> Vec<(&str, &str)> {
+        self.clocks.values().filter_map(Clocks::get).collect()
+    }
+
+    /// Keep only the clocks of the connections `keep` is true for
+    pub fn retain_clocks(&mut self, mut keep: impl FnMut(usize) -> bool) {
+        self.clocks.retain(|connection, _| keep(*connection));
+    }
+
+    pub fn markets(&self) -> impl Iterator<Item = &MarketBook> {
+        self.markets.values()
+    }
+
+    /// Forget a market, once it has closed or is no longer subscribed
+    pub fn remove(&mut self, market_id: &str) -> Option<MarketBook> {
+        self.markets.remove(market_id)
+    }
+
+    /// Keep only the markets `keep` is true for
+    pub fn retain(&mut self, mut keep: impl FnMut(&MarketBook) -> bool) {
+        self.markets.retain(|_, market| keep(market));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn mcm(frame: &str) -> MarketChangeMessage {
+        serde_json::from_str(frame).unwrap()
+    }
+
+    #[test]
+    fn apply_image_then_deltas() {
+        let mut sut = MarketCache::new();
+        let image = mcm(
+            r#"{"pt":1,"mc":[{"id":"1.1","img":true,"tv":50,"rc":[{"id":7,"atb":[[1.95,12.5],[1.94,110]],"atl":[[1.96,40]],"batb":[[0,1.95,12.5],[1,1.94,110]],"trd":[[1.95,50]],"ltp":1.95,"tv":50}]}]}"#,
+        );
+        assert!(sut.update(1, &image).is_empty());
+
+        sut.update(1, &mcm(
+            r#"{"pt":2,"mc":[{"id":"1.1","rc":[{"id":7,"atb":[[1.95,0],[1.93,5]],"atl":[[1.96,20]],"batb":[[0,1.94,110],[1,0,0]]},{"id":8,"hc":-1.5,"atl":[[3.5,2]],"spn":3.4}]}]}"#,
+        ));
+        let market = sut.market("1.1").unwrap();
+        assert_eq!(market.pt, 2);
+        assert_eq!(market.tv, Some(50.));
+        let runner = market.runner(7, 0.).unwrap();
+        assert_eq!(runner.atb.levels(), [[1.93, 5.], [1.94, 110.]]);
+        assert_eq!(runner.atl.size_at(1.96), Some(20.));
+        assert_eq!(runner.batb.levels().collect::<Vec<_>>(), [&[1.94, 110.]]);
+        assert_eq!(runner.ltp, Some(1.95));
+        let handicap = market.runner(8, -1.5).unwrap();
+        assert_eq!(handicap.spn, Some(3.4));
+        assert!(market.runner(8, 0.).is_none());
+
+        // A new image starts over
+        sut.update(
+            1,
+            &mcm(r#"{"pt":3,"mc":[{"id":"1.1","img":true,"rc":[{"id":7,"atb":[[2,1]]}]}]}"#),
+        );
+        let market = sut.market("1.1").unwrap();
+        assert_eq!(market.tv, None);
+        assert_eq!(market.runners.len(), 1);
+        assert_eq!(market.runner(7, 0.).unwrap().atb.levels(), [[2., 1.]]);
+    }
+
+    #[test]
+    fn clocks_of_a_replaced_subscription_are_ignored() {
+        let mut sut = MarketCache::new();
+        sut.update(
+            1,
+            &mcm(r#"{"id":2,"initialClk":"i2","clk":"a","pt":1,"ct":"SUB_IMAGE","mc":[]}"#),
+        );
+        sut.update(1, &mcm(r#"{"id":2,"clk":"b","pt":2,"mc":[]}"#));
+        assert_eq!(sut.clocks(), [("i2", "b")]);
+
+        sut.update(
+            1,
+            &mcm(r#"{"id":3,"initialClk":"i3","clk":"c","pt":3,"ct":"RESUB_DELTA","mc":[]}"#),
+        );
+        // Sent before betfair took the new subscription, arriving late
+        sut.update(1, &mcm(r#"{"id":2,"clk":"stale","pt":4,"mc":[]}"#));
+        assert_eq!(sut.clocks(), [("i3", "c")]);
+        sut.update(1, &mcm(r#"{"id":3,"clk":"d","pt":5,"mc":[]}"#));
+        assert_eq!(sut.clocks(), [("i3", "d")]);
+    }
+
+    fn definition(status: &str, in_play: bool, bet_delay: u32, runners: &str) -> String {
+        format!(
+            r#"{{"status":"{}","bettingType":"ODDS","marketType":"WIN","eventTypeId":"7","eventId":"1","version":1,"inPlay":{},"betDelay":{},"bspMarket":false,"turnInPlayEnabled":true,"persistenceEnabled":true,"crossMatching":true,"runnersVoidable":false,"complete":true,"bspReconciled":false,"numberOfWinners":1,"numberOfActiveRunners":2,"marketTime":"2024-07-15T19:00:00.000Z","runners":[{}]}}"#,
+            status, in_play, bet_delay, runners
+        )
+    }
+
+    #[test]
+    fn definition_changes_are_events() {
+        let mut sut = MarketCache::new();
+        let open = definition(
+            "OPEN",
+            false,
+            0,
+            r#"{"id":7,"status":"ACTIVE","sortPriority":1,"adjustmentFactor":40},{"id":8,"status":"ACTIVE","sortPriority":2,"adjustmentFactor":60}"#,
+        );
+        let frame = format!(
+            r#"{{"pt":1,"mc":[{{"id":"1.1","img":true,"marketDefinition":{}}}]}}"#,
+            open
+        );
+        assert!(sut.update(1, &mcm(&frame)).is_empty());
+        // Same definition again, nothing to tell
+        assert!(sut.update(1, &mcm(&frame)).is_empty());
+
+        // Runner 8 is a non runner, the market suspends while it is taken out
+        let suspended = definition(
+            "SUSPENDED",
+            false,
+            0,
+            r#"{"id":7,"status":"ACTIVE","sortPriority":1,"adjustmentFactor":100},{"id":8,"status":"REMOVED","sortPriority":2,"adjustmentFactor":60}"#,
+        );
+        let events = sut.update(
+            1,
+            &mcm(&format!(
+                r#"{{"pt":2,"mc":[{{"id":"1.1","marketDefinition":{}}}]}}"#,
+                suspended
+            )),
+        );
+        let market_id = String::from("1.1");
+        assert_eq!(
+            events,
+            [
+                MarketEvent::Status {
+                    market_id: market_id.clone(),
+                    from: MarketStatus::Open,
+                    to: MarketStatus::Suspended,
+                },
+                MarketEvent::AdjustmentFactor {
+                    market_id: market_id.clone(),
+                    runner: RunnerKey::new(7, 0.),
+                    from: Some(40.),
+                    to: Some(100.),
+                },
+                MarketEvent::RunnerStatus {
+                    market_id: market_id.clone(),
+                    runner: RunnerKey::new(8, 0.),
+                    from: RunnerStatus::Active,
+                    to: RunnerStatus::Removed,
+                },
+            ]
+        );
+
+        // A new image is compared with what we had before it
+        let in_play = definition(
+            "OPEN",
+            true,
+            5,
+            r#"{"id":7,"status":"ACTIVE","sortPriority":1,"adjustmentFactor":100}"#,
+        );
+        let events = sut.update(
+            1,
+            &mcm(&format!(
+                r#"{{"pt":3,"mc":[{{"id":"1.1","img":true,"marketDefinition":{}}}]}}"#,
+                in_play
+            )),
+        );
+        assert_eq!(
+            events,
+            [
+                MarketEvent::Status {
+                    market_id: market_id.clone(),
+                    from: MarketStatus::Suspended,
+                    to: MarketStatus::Open,
+                },
+                MarketEvent::InPlay {
+                    market_id: market_id.clone(),
+                    in_play: true,
+                },
+                MarketEvent::BetDelay {
+                    market_id,
+                    from: 0,
+                    to: 5,
+                },
+            ]
+        );
+    }
+
+    // A runner as a test generates it, prices are indexes into a few ticks
+    #[derive(Debug, Clone)]
+    struct RunnerState {
+        atb: BTreeMap<usize, u32>,
+        atl: BTreeMap<usize, u32>,
+        trd: BTreeMap<usize, u32>,
+        batb: BTreeMap<usize, (usize, u32)>,
+        ltp: usize,
+        tv: u32,
+    }
+
+    const TICKS: [f64; 8] = [1.5, 1.51, 1.52, 1.53, 1.54, 1.55, 1.56, 1.57];
+    const RUNNERS: [(i64, f64); 3] = [(7, 0.), (8, 0.), (8, 0.5)];
+
+    fn runner_state() -> impl Strategy<Value = RunnerState> {
+        let ladder = || prop::collection::btree_map(0..TICKS.len(), 1..1000u32, 0..6);
+        (
+            ladder(),
+            ladder(),
+            ladder(),
+            prop::collection::btree_map(0..3usize, (0..TICKS.len(), 1..1000u32), 0..3),
+            0..TICKS.len(),
+            1..100_000u32,
+        )
+            .prop_map(|(atb, atl, trd, batb, ltp, tv)| RunnerState {
+                atb,
+                atl,
+                trd,
+                batb,
+                ltp,
+                tv,
+            })
+    }
+
+    fn market_states() -> impl Strategy<Value = Vec<Vec<RunnerState>>> {
+        prop::collection::vec(prop::collection::vec(runner_state(), RUNNERS.len()), 1..8)
+    }
+
+    // Sizes that differ from `from`, with 0 for prices that went away
+    fn ladder_delta(from: &BTreeMap<usize, u32>, to: &BTreeMap<usize, u32>) -> Vec<PriceSize> {
+        let removed = from
+            .keys()
+            .filter(|tick| !to.contains_key(tick))
+            .map(|tick| [TICKS[*tick], 0.]);
+        let changed = to
+            .iter()
+            .filter(|(tick, size)| from.get(tick) != Some(size))
+            .map(|(tick, size)| [TICKS[*tick], *size as f64]);
+        removed.chain(changed).collect()
+    }
+
+    fn level_delta(
+        from: &BTreeMap<usize, (usize, u32)>,
+        to: &BTreeMap<usize, (usize, u32)>,
+    ) -> Vec<LevelPriceSize> {
+        let removed = from
+            .keys()
+            .filter(|level| !to.contains_key(level))
+            .map(|level| [*level as f64, 0., 0.]);
+        let changed = to
+            .iter()
+            .filter(|(level, value)| from.get(level) != Some(value))
+            .map(|(level, (tick, size))| [*level as f64, TICKS[*tick], *size as f64]);
+        removed.chain(changed).collect()
+    }
+
+    // The change from `from` to `to`, an image when there is nothing to start from
+    fn market_change(from: Option<&[RunnerState]>, to: &[RunnerState]) -> MarketChange {
+        let empty = RunnerState {
+            atb: BTreeMap::new(),
+            atl: BTreeMap::new(),
+            trd: BTreeMap::new(),
+            batb: BTreeMap::new(),
+            ltp: usize::MAX,
+            tv: 0,
+        };
+        let rc = RUNNERS
+            .iter()
+            .zip(to)
+            .enumerate()
+            .map(|(index, ((id, hc), to))| {
+                let from = from.map_or(&empty, |from| &from[index]);
+                let json = serde_json::json!({
+                    "id": id,
+                    "hc": hc,
+                    "atb": ladder_delta(&from.atb, &to.atb),
+                    "atl": ladder_delta(&from.atl, &to.atl),
+                    "trd": ladder_delta(&from.trd, &to.trd),
+                    "batb": level_delta(&from.batb, &to.batb),
+                    "ltp": (from.ltp != to.ltp).then(|| TICKS[to.ltp]),
+                    "tv": (from.tv != to.tv).then_some(to.tv),
+                });
+                serde_json::from_value(json).unwrap()
+            })
+            .collect();
+        MarketChange {
+            id: String::from("1.1"),
+            img: Some(from.is_none()),
+            con: None,
+            tv: None,
+            market_definition: None,
+            rc: Some(rc),
+        }
+    }
+
+    fn message(pt: i64, change: MarketChange) -> MarketChangeMessage {
+        MarketChangeMessage {
+            id: None,
+            ct: None,
+            clk: None,
+            initial_clk: None,
+            pt,
+            heartbeat_ms: None,
+            conflate_ms: None,
+            segment_type: None,
+            status: None,
+            mc: Some(vec![change]),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn deltas_replay_to_the_final_image(states in market_states()) {
+            let mut replayed = MarketCache::new();
+            replayed.update(1, &message(0, market_change(None, &states[0])));
+            for (pt, pair) in states.windows(2).enumerate() {
+                replayed.update(1, &message(pt as i64 + 1, market_change(Some(&pair[0]), &pair[1])));
+            }
+
+            let mut imaged = MarketCache::new();
+            let last = states.len() - 1;
+            imaged.update(1, &message(last as i64, market_change(None, &states[last])));
+            prop_assert_eq!(replayed, imaged);
+        }
+    }
+}
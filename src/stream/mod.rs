@@ -1,20 +1,39 @@
 use color_eyre::eyre;
-use rustls::{ClientConfig, ClientConnection, RootCertStore, Stream, StreamOwned};
-use serde::{de, Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
 use std::{
-    io::{self, BufRead, BufReader, Read, Write},
+    collections::VecDeque,
+    fmt,
+    io::{self, BufRead, ErrorKind, Write},
     net::TcpStream,
-    str::FromStr,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        mpsc::{self, channel, Receiver, Sender},
-        Arc, Mutex,
-    },
-    thread,
     time::Duration,
 };
 
-mod model;
+mod async_client;
+mod endpoint;
+#[cfg(test)]
+mod fake_server;
+mod market_cache;
+pub mod model;
+mod order_cache;
+mod race_cache;
+mod segment;
+mod session;
+mod snapshot;
+mod subscriptions;
+mod telemetry;
+
+pub use async_client::*;
+pub use endpoint::*;
+pub use market_cache::*;
+pub use order_cache::*;
+pub use race_cache::*;
+pub use segment::*;
+pub use session::*;
+pub use snapshot::*;
+pub use subscriptions::*;
+pub use telemetry::*;
+
+use model::{BettingType, ResponseMessage, StatusResponse};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -42,80 +61,518 @@ impl SetId for AuthenticationMessage {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MarketFilter {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MarketDataField {
+    /// Best prices including virtual prices, depth set by ladder levels
+    ExBestOffersDisp,
+    /// Best prices not including virtual prices, depth set by ladder levels
+    ExBestOffers,
+    /// Full available to back/lay ladder
+    ExAllOffers,
+    /// Full traded ladder
+    ExTraded,
+    /// Market and runner traded volume
+    ExTradedVol,
+    /// Last traded price
+    ExLtp,
+    /// Market definition
+    ExMarketDef,
+    /// Starting price ladders
+    SpTraded,
+    /// Starting price projections
+    SpProjected,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
     country_codes: Option<Vec<String>>,
-    betting_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    betting_types: Option<Vec<BettingType>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     turn_in_play_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     market_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     venues: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     market_ids: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     event_type_ids: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     event_ids: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     bsp_market: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     race_types: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MarketDataFilter {
-    ladder_levels: Option<i32>,
-    fields: Option<Vec<String>>,
+fn to_strings<I, S>(values: I) -> Option<Vec<String>>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    Some(values.into_iter().map(Into::into).collect())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MarketSubscriptionMessage {
+impl MarketFilter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn market_ids<I, S>(mut self, ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.market_ids = to_strings(ids);
+        self
+    }
+
+    pub fn event_type_ids<I, S>(mut self, ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.event_type_ids = to_strings(ids);
+        self
+    }
+
+    pub fn event_ids<I, S>(mut self, ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.event_ids = to_strings(ids);
+        self
+    }
+
+    pub fn country_codes<I, S>(mut self, codes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.country_codes = to_strings(codes);
+        self
+    }
+
+    pub fn market_types<I, S>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.market_types = to_strings(types);
+        self
+    }
+
+    pub fn venues<I, S>(mut self, venues: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.venues = to_strings(venues);
+        self
+    }
+
+    pub fn race_types<I, S>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.race_types = to_strings(types);
+        self
+    }
+
+    pub fn betting_types(mut self, types: impl IntoIterator<Item = BettingType>) -> Self {
+        self.betting_types = Some(types.into_iter().collect());
+        self
+    }
+
+    pub fn bsp_market(mut self, bsp_market: bool) -> Self {
+        self.bsp_market = Some(bsp_market);
+        self
+    }
+
+    pub fn turn_in_play_enabled(mut self, enabled: bool) -> Self {
+        self.turn_in_play_enabled = Some(enabled);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketDataFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ladder_levels: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<Vec<MarketDataField>>,
+}
+
+impl MarketDataFilter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn fields(mut self, fields: impl IntoIterator<Item = MarketDataField>) -> Self {
+        self.fields = Some(fields.into_iter().collect());
+        self
+    }
+
+    /// Depth of the best offers ladders, betfair allows 1 to 10
+    pub fn ladder_levels(mut self, levels: u8) -> Self {
+        self.ladder_levels = Some(levels.clamp(1, 10));
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketSubscriptionMessage {
     op: String,
     id: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
     segmentation_enabled: Option<bool>,
-    conflate_ms: Option<i32>,
-    heartbeat_ms: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conflate_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heartbeat_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     initial_clk: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     clk: Option<String>,
     market_filter: MarketFilter,
     market_data_filter: MarketDataFilter,
 }
 
-trait SetId {
+impl MarketSubscriptionMessage {
+    pub fn new(market_filter: MarketFilter, market_data_filter: MarketDataFilter) -> Self {
+        Self {
+            op: String::from("marketSubscription"),
+            id: 0,
+            segmentation_enabled: None,
+            conflate_ms: None,
+            heartbeat_ms: None,
+            initial_clk: None,
+            clk: None,
+            market_filter,
+            market_data_filter,
+        }
+    }
+
+    pub fn segmentation_enabled(mut self, enabled: bool) -> Self {
+        self.segmentation_enabled = Some(enabled);
+        self
+    }
+
+    /// Merge changes over this window into one message, 0 disables conflation
+    pub fn conflate_ms(mut self, conflate_ms: u64) -> Self {
+        self.conflate_ms = Some(conflate_ms);
+        self
+    }
+
+    /// Betfair allows 500 to 5000 ms between heartbeats
+    pub fn heartbeat_ms(mut self, heartbeat_ms: u64) -> Self {
+        self.heartbeat_ms = Some(heartbeat_ms.clamp(500, 5000));
+        self
+    }
+
+    /// Resume from the clocks of an earlier subscription instead of a new full image
+    pub fn resume(mut self, initial_clk: &str, clk: &str) -> Self {
+        self.initial_clk = Some(String::from(initial_clk));
+        self.clk = Some(String::from(clk));
+        self
+    }
+
+    pub fn market_filter(mut self, market_filter: MarketFilter) -> Self {
+        self.market_filter = market_filter;
+        self
+    }
+}
+
+impl SetId for MarketSubscriptionMessage {
+    fn set_id(&mut self, id: usize) {
+        self.id = id;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderFilter {
+    /// Send matched back/lay ladders, defaults to true on betfair
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include_overall_position: Option<bool>,
+    /// Only send orders placed with these strategy refs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    customer_strategy_refs: Option<Vec<String>>,
+    /// Also send matched ladders partitioned by strategy ref
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partition_matched_by_strategy_ref: Option<bool>,
+}
+
+impl OrderFilter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn include_overall_position(mut self, include: bool) -> Self {
+        self.include_overall_position = Some(include);
+        self
+    }
+
+    pub fn customer_strategy_refs<I, S>(mut self, refs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.customer_strategy_refs = to_strings(refs);
+        self
+    }
+
+    pub fn partition_matched_by_strategy_ref(mut self, partition: bool) -> Self {
+        self.partition_matched_by_strategy_ref = Some(partition);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderSubscriptionMessage {
+    op: String,
+    id: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    segmentation_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conflate_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heartbeat_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_clk: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clk: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order_filter: Option<OrderFilter>,
+}
+
+impl OrderSubscriptionMessage {
+    pub fn new() -> Self {
+        Self {
+            op: String::from("orderSubscription"),
+            id: 0,
+            segmentation_enabled: None,
+            conflate_ms: None,
+            heartbeat_ms: None,
+            initial_clk: None,
+            clk: None,
+            order_filter: None,
+        }
+    }
+
+    pub fn order_filter(mut self, order_filter: OrderFilter) -> Self {
+        self.order_filter = Some(order_filter);
+        self
+    }
+
+    pub fn segmentation_enabled(mut self, enabled: bool) -> Self {
+        self.segmentation_enabled = Some(enabled);
+        self
+    }
+
+    /// Merge changes over this window into one message, 0 disables conflation
+    pub fn conflate_ms(mut self, conflate_ms: u64) -> Self {
+        self.conflate_ms = Some(conflate_ms);
+        self
+    }
+
+    /// Betfair allows 500 to 5000 ms between heartbeats
+    pub fn heartbeat_ms(mut self, heartbeat_ms: u64) -> Self {
+        self.heartbeat_ms = Some(heartbeat_ms.clamp(500, 5000));
+        self
+    }
+
+    /// Resume from the clocks of an earlier subscription instead of a new full image
+    pub fn resume(mut self, initial_clk: &str, clk: &str) -> Self {
+        self.initial_clk = Some(String::from(initial_clk));
+        self.clk = Some(String::from(clk));
+        self
+    }
+}
+
+impl Default for OrderSubscriptionMessage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SetId for OrderSubscriptionMessage {
+    fn set_id(&mut self, id: usize) {
+        self.id = id;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeartbeatMessage {
+    op: String,
+    id: usize,
+}
+
+impl HeartbeatMessage {
+    pub fn new() -> Self {
+        Self {
+            op: String::from("heartbeat"),
+            id: 0,
+        }
+    }
+}
+
+impl Default for HeartbeatMessage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SetId for HeartbeatMessage {
+    fn set_id(&mut self, id: usize) {
+        self.id = id;
+    }
+}
+
+/// Race position data, betfair sends every race the app key has access to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RaceSubscriptionMessage {
+    op: String,
+    id: usize,
+}
+
+impl RaceSubscriptionMessage {
+    pub fn new() -> Self {
+        Self {
+            op: String::from("raceSubscription"),
+            id: 0,
+        }
+    }
+}
+
+impl Default for RaceSubscriptionMessage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SetId for RaceSubscriptionMessage {
+    fn set_id(&mut self, id: usize) {
+        self.id = id;
+    }
+}
+
+pub trait SetId {
     fn set_id(&mut self, id: usize);
 }
 
-type MarketCache = Vec<usize>; // Placeholder
-type StatusCache = Vec<usize>; // Placeholder
+/// Any request, for sending different kinds down the same channel
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum RequestMessage {
+    Authentication(AuthenticationMessage),
+    MarketSubscription(MarketSubscriptionMessage),
+    OrderSubscription(OrderSubscriptionMessage),
+    RaceSubscription(RaceSubscriptionMessage),
+    Heartbeat(HeartbeatMessage),
+}
+
+impl SetId for RequestMessage {
+    fn set_id(&mut self, id: usize) {
+        match self {
+            Self::Authentication(message) => message.set_id(id),
+            Self::MarketSubscription(message) => message.set_id(id),
+            Self::OrderSubscription(message) => message.set_id(id),
+            Self::RaceSubscription(message) => message.set_id(id),
+            Self::Heartbeat(message) => message.set_id(id),
+        }
+    }
+}
+
+impl From<AuthenticationMessage> for RequestMessage {
+    fn from(message: AuthenticationMessage) -> Self {
+        Self::Authentication(message)
+    }
+}
+
+impl From<MarketSubscriptionMessage> for RequestMessage {
+    fn from(message: MarketSubscriptionMessage) -> Self {
+        Self::MarketSubscription(message)
+    }
+}
+
+impl From<OrderSubscriptionMessage> for RequestMessage {
+    fn from(message: OrderSubscriptionMessage) -> Self {
+        Self::OrderSubscription(message)
+    }
+}
+
+impl From<RaceSubscriptionMessage> for RequestMessage {
+    fn from(message: RaceSubscriptionMessage) -> Self {
+        Self::RaceSubscription(message)
+    }
+}
+
+impl From<HeartbeatMessage> for RequestMessage {
+    fn from(message: HeartbeatMessage) -> Self {
+        Self::Heartbeat(message)
+    }
+}
+
+/// A frame that isn't a message we know how to read. Only that line is
+/// lost, the connection itself is fine.
+#[derive(Debug)]
+pub struct ParseError {
+    pub frame: String,
+    source: serde_json::Error,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unable to read frame: {}", self.source)
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
 
 pub struct LinesCodec {
-    stream: io::BufReader<StreamOwned<ClientConnection, TcpStream>>,
+    stream: io::BufReader<Box<dyn Connection>>,
+    // The socket under `stream`, kept to set timeouts on
+    sock: TcpStream,
     num_msg: usize,
+    // Messages read while waiting for a status response
+    pending: VecDeque<ResponseMessage>,
+    // Partial line kept between reads that timed out
+    line: Vec<u8>,
 }
 
 impl LinesCodec {
     pub fn new() -> eyre::Result<Self> {
-        let root_store = RootCertStore {
-            // TODO only add the server cert for the endpint i need
-            roots: webpki_roots::TLS_SERVER_ROOTS.into(),
-        };
-        let mut config = ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-        let server_name = "stream-api.betfair.com".try_into()?;
-        let mut conn = ClientConnection::new(Arc::new(config), server_name)?;
-        let mut sock = TcpStream::connect("stream-api.betfair.com:443")?;
-        // let mut tls = rustls::Stream::new(&mut conn, &mut sock);
-        let mut tls = rustls::StreamOwned::new(conn, sock);
-        // Both BufReader and LineWriter need to own a stream
-        // We can clone the stream to simulate splitting Tx & Rx
-        // let writer = io::LineWriter::new(tls.sock.try_clone()?);
-        let stream = io::BufReader::new(tls);
+        Self::connect(&StreamEndpoint::default())
+    }
+
+    pub fn connect(endpoint: &StreamEndpoint) -> eyre::Result<Self> {
+        let (connection, sock) = endpoint.open()?;
+        let stream = io::BufReader::new(connection);
         Ok(Self {
-            // tls,
-            // reader,
-            // writer,
             stream,
+            sock,
             num_msg: 0,
+            pending: VecDeque::new(),
+            line: Vec::new(),
         })
     }
 
-    pub fn send_message<T>(&mut self, mut message: T) -> eyre::Result<()>
+    /// Returns the id given to the message, betfair echoes it in the status response.
+    pub fn send_message<T>(&mut self, mut message: T) -> eyre::Result<usize>
     where
         T: Serialize + SetId,
     {
@@ -123,18 +580,251 @@ impl LinesCodec {
         message.set_id(self.num_msg);
 
         let json = serde_json::to_string(&message)?;
-        self.stream.get_mut().write_all(&json.as_bytes())?;
-        self.stream.get_mut().write_all(&[b'\r', b'\n'])?;
-        let num = self.stream.get_mut().flush()?;
+        self.stream.get_mut().write_all(json.as_bytes())?;
+        self.stream.get_mut().write_all(b"\r\n")?;
+        self.stream.get_mut().flush()?;
+        Ok(self.num_msg)
+    }
+
+    /// Wait for the next message, fails once the read timeout passes without one
+    pub fn read_message(&mut self) -> eyre::Result<ResponseMessage> {
+        self.try_read_message()?
+            .ok_or_else(|| eyre::eyre!("no message from betfair within the read timeout"))
+    }
+
+    /// Like `read_message` but returns `None` when the read timeout passes without a full message.
+    pub fn try_read_message(&mut self) -> eyre::Result<Option<ResponseMessage>> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(Some(message));
+        }
+        self.read_line()
+    }
+
+    /// Limit how long a read blocks, `None` blocks until a message arrives.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> eyre::Result<()> {
+        self.sock.set_read_timeout(timeout)?;
         Ok(())
     }
 
-    pub fn read_message(&mut self) -> eyre::Result<model::ResponseMessage> {
-        let mut line = String::new();
-        self.stream.read_line(&mut line)?;
-        line.pop(); // Remove \r
-        line.pop(); // Remove \n
-        let res = serde_json::from_str(line.as_str())?;
-        Ok(res)
+    pub fn authenticate(
+        &mut self,
+        authentication: AuthenticationMessage,
+    ) -> eyre::Result<StatusResponse> {
+        let id = self.send_message(authentication)?;
+        self.read_status(id)
+    }
+
+    /// Subscribe to markets, replaces any earlier market subscription on this connection.
+    pub fn subscribe_markets(
+        &mut self,
+        subscription: MarketSubscriptionMessage,
+    ) -> eyre::Result<StatusResponse> {
+        let id = self.send_message(subscription)?;
+        self.read_status(id)
+    }
+
+    /// Subscribe to our own orders, replaces any earlier order subscription on this connection.
+    pub fn subscribe_orders(
+        &mut self,
+        subscription: OrderSubscriptionMessage,
+    ) -> eyre::Result<StatusResponse> {
+        let id = self.send_message(subscription)?;
+        self.read_status(id)
+    }
+
+    /// Race positions of every race with tracking data, sent as `Rcm` messages.
+    pub fn subscribe_races(&mut self) -> eyre::Result<StatusResponse> {
+        let id = self.send_message(RaceSubscriptionMessage::new())?;
+        self.read_status(id)
+    }
+
+    /// Check that the connection is alive, betfair answers with a status.
+    pub fn heartbeat(&mut self) -> eyre::Result<StatusResponse> {
+        let id = self.send_message(HeartbeatMessage::new())?;
+        self.read_status(id)
+    }
+
+    /// Wait for the status answering request `id`, anything else read meanwhile is
+    /// kept for `read_message`. A status without an id is betfair closing the
+    /// connection and is returned as well. Fails once a read times out.
+    pub fn read_status(&mut self, id: usize) -> eyre::Result<StatusResponse> {
+        loop {
+            let read = match self.read_line() {
+                // Whoever reads the messages would only skip it too
+                Err(e) if e.is::<ParseError>() => continue,
+                read => read?,
+            };
+            match read {
+                Some(ResponseMessage::Status(status))
+                    if status.id.is_none_or(|status_id| status_id == id) =>
+                {
+                    return Ok(status)
+                }
+                Some(other) => self.pending.push_back(other),
+                None => {
+                    return Err(eyre::eyre!(
+                        "no status for request {} within the read timeout",
+                        id
+                    ))
+                }
+            }
+        }
+    }
+
+    // A line that doesn't parse is dropped and returned as a `ParseError`
+    fn read_line(&mut self) -> eyre::Result<Option<ResponseMessage>> {
+        // read_until keeps what it got before a timeout in the buffer
+        match self.stream.read_until(b'\n', &mut self.line) {
+            Ok(_) if !self.line.ends_with(b"\n") => Err(eyre::eyre!("stream closed by betfair")),
+            Ok(_) => {
+                let frame = self.line.trim_ascii_end();
+                let res = serde_json::from_slice(frame).map_err(|source| ParseError {
+                    frame: String::from_utf8_lossy(frame).into_owned(),
+                    source,
+                });
+                self.line.clear();
+                Ok(Some(res?))
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fake_server::{FakeServer, Reply};
+    use serde_json::json;
+
+    #[test]
+    fn serialize_market_subscription() {
+        let mut msg = MarketSubscriptionMessage::new(
+            MarketFilter::new()
+                .market_ids(["1.230312812"])
+                .event_type_ids(["7"])
+                .country_codes(["GB", "IE"])
+                .market_types(["WIN"])
+                .betting_types([BettingType::Odds])
+                .bsp_market(true)
+                .turn_in_play_enabled(true),
+            MarketDataFilter::new()
+                .fields([
+                    MarketDataField::ExBestOffers,
+                    MarketDataField::ExLtp,
+                    MarketDataField::ExMarketDef,
+                ])
+                .ladder_levels(3),
+        )
+        .conflate_ms(0)
+        .heartbeat_ms(1000)
+        .segmentation_enabled(true);
+        msg.set_id(2);
+
+        let expected = json!({
+            "op": "marketSubscription",
+            "id": 2,
+            "segmentationEnabled": true,
+            "conflateMs": 0,
+            "heartbeatMs": 1000,
+            "marketFilter": {
+                "countryCodes": ["GB", "IE"],
+                "bettingTypes": ["ODDS"],
+                "turnInPlayEnabled": true,
+                "marketTypes": ["WIN"],
+                "marketIds": ["1.230312812"],
+                "eventTypeIds": ["7"],
+                "bspMarket": true
+            },
+            "marketDataFilter": {
+                "ladderLevels": 3,
+                "fields": ["EX_BEST_OFFERS", "EX_LTP", "EX_MARKET_DEF"]
+            }
+        });
+        assert_eq!(serde_json::to_value(&msg).unwrap(), expected);
+    }
+
+    #[test]
+    fn serialize_order_subscription() {
+        let mut msg = OrderSubscriptionMessage::new()
+            .order_filter(
+                OrderFilter::new()
+                    .include_overall_position(true)
+                    .customer_strategy_refs(["scalp"])
+                    .partition_matched_by_strategy_ref(true),
+            )
+            .segmentation_enabled(true)
+            .conflate_ms(0)
+            .heartbeat_ms(5000)
+            .resume("GpOjqP4DHqC3ov4D", "AAAAAAAAAAAAAA==");
+        msg.set_id(3);
+
+        let expected = json!({
+            "op": "orderSubscription",
+            "id": 3,
+            "segmentationEnabled": true,
+            "conflateMs": 0,
+            "heartbeatMs": 5000,
+            "initialClk": "GpOjqP4DHqC3ov4D",
+            "clk": "AAAAAAAAAAAAAA==",
+            "orderFilter": {
+                "includeOverallPosition": true,
+                "customerStrategyRefs": ["scalp"],
+                "partitionMatchedByStrategyRef": true
+            }
+        });
+        assert_eq!(serde_json::to_value(&msg).unwrap(), expected);
+    }
+
+    #[test]
+    fn clamp_subscription_limits() {
+        let msg = MarketSubscriptionMessage::new(
+            MarketFilter::new(),
+            MarketDataFilter::new().ladder_levels(20),
+        )
+        .heartbeat_ms(100);
+        assert_eq!(msg.heartbeat_ms, Some(500));
+        assert_eq!(msg.market_data_filter.ladder_levels, Some(10));
+    }
+
+    fn answer_everything(_: usize, request: &serde_json::Value) -> Reply {
+        Reply::send([FakeServer::status_ok(request["id"].as_u64().unwrap())])
+    }
+
+    #[test]
+    fn plain_tcp_to_local_mock() {
+        let server = FakeServer::start_plain(answer_everything);
+        let mut codec =
+            LinesCodec::connect(&StreamEndpoint::plain("127.0.0.1", server.port())).unwrap();
+        assert!(matches!(
+            codec.read_message().unwrap(),
+            ResponseMessage::Connection(_)
+        ));
+        let status = codec
+            .authenticate(AuthenticationMessage::new("key", "token"))
+            .unwrap();
+        assert_eq!(status.id, Some(1));
+    }
+
+    #[test]
+    fn pinned_ca_bundle() {
+        let server = FakeServer::start(answer_everything);
+        let bundle = std::env::temp_dir().join(format!("bfg-ca-{}.pem", server.port()));
+        std::fs::write(&bundle, server.ca_pem()).unwrap();
+
+        let pinned = StreamEndpoint::new("localhost", server.port())
+            .ca_bundle(&bundle)
+            .unwrap();
+        let mut codec = LinesCodec::connect(&pinned).unwrap();
+        assert!(codec.read_message().is_ok());
+        std::fs::remove_file(&bundle).unwrap();
+        // The fake server takes one client at a time
+        drop(codec);
+
+        // The public roots don't know the fake certificate
+        let public = StreamEndpoint::new("localhost", server.port());
+        assert!(LinesCodec::connect(&public)
+            .and_then(|mut codec| codec.read_message())
+            .is_err());
     }
 }
@@ -0,0 +1,336 @@
+// Market Change Message, the price data of the market stream
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// [price, size]
+pub type PriceSize = [f64; 2];
+/// [level, price, size]
+pub type LevelPriceSize = [f64; 3];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ChangeType {
+    SubImage,
+    ResubDelta,
+    Heartbeat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SegmentType {
+    SegStart,
+    Seg,
+    SegEnd,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketChangeMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    /// Missing for a plain delta
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ct: Option<ChangeType>,
+    /// Token used to resume the stream from this point
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clk: Option<String>,
+    /// Token used to resume the stream from the initial image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_clk: Option<String>,
+    /// Publish time in milliseconds since epoch
+    pub pt: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflate_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub segment_type: Option<SegmentType>,
+    /// Set to 503 when the stream is serving stale data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mc: Option<Vec<MarketChange>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketChange {
+    pub id: String,
+    /// Replace the cached market instead of applying a delta
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub img: Option<bool>,
+    /// Conflated, more than one update merged into this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub con: Option<bool>,
+    /// Total amount matched on the market
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tv: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub market_definition: Option<MarketDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rc: Option<Vec<RunnerChange>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunnerChange {
+    /// Selection id
+    pub id: i64,
+    /// Handicap, only set on handicap markets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hc: Option<f64>,
+    /// Available to back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub atb: Option<Vec<PriceSize>>,
+    /// Available to lay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub atl: Option<Vec<PriceSize>>,
+    /// Best available to back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batb: Option<Vec<LevelPriceSize>>,
+    /// Best available to lay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batl: Option<Vec<LevelPriceSize>>,
+    /// Best display available to back, virtual prices included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bdatb: Option<Vec<LevelPriceSize>>,
+    /// Best display available to lay, virtual prices included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bdatl: Option<Vec<LevelPriceSize>>,
+    /// Traded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trd: Option<Vec<PriceSize>>,
+    /// Last traded price
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ltp: Option<f64>,
+    /// Total traded volume on the runner
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tv: Option<f64>,
+    /// Starting price near
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spn: Option<f64>,
+    /// Starting price far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spf: Option<f64>,
+    /// Starting price back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spb: Option<Vec<PriceSize>>,
+    /// Starting price lay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spl: Option<Vec<PriceSize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MarketStatus {
+    Inactive,
+    Open,
+    Suspended,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum BettingType {
+    Odds,
+    Line,
+    Range,
+    AsianHandicapDoubleLine,
+    AsianHandicapSingleLine,
+    FixedOdds,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PriceLadderType {
+    Classic,
+    Finest,
+    LineRange,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceLadderDefinition {
+    #[serde(rename = "type")]
+    pub ladder_type: PriceLadderType,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketDefinition {
+    pub status: MarketStatus,
+    pub betting_type: BettingType,
+    pub market_type: String,
+    pub event_type_id: String,
+    pub event_id: String,
+    pub version: i64,
+    pub in_play: bool,
+    pub bet_delay: u32,
+    pub bsp_market: bool,
+    pub turn_in_play_enabled: bool,
+    pub persistence_enabled: bool,
+    pub cross_matching: bool,
+    pub runners_voidable: bool,
+    pub complete: bool,
+    pub bsp_reconciled: bool,
+    pub number_of_winners: u32,
+    pub number_of_active_runners: u32,
+    pub market_time: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_date: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suspend_time: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settled_time: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub venue: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub race_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub each_way_divisor: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discount_allowed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub market_base_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regulators: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price_ladder_definition: Option<PriceLadderDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_max_unit: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_min_unit: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_interval: Option<f64>,
+    #[serde(default)]
+    pub runners: Vec<RunnerDefinition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RunnerStatus {
+    Active,
+    Winner,
+    Loser,
+    Placed,
+    Removed,
+    RemovedVacant,
+    Hidden,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunnerDefinition {
+    /// Selection id
+    pub id: i64,
+    pub status: RunnerStatus,
+    pub sort_priority: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hc: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adjustment_factor: Option<f64>,
+    /// Betfair starting price, set once the market is reconciled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bsp: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub removal_date: Option<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ResponseMessage;
+    use super::*;
+
+    const SUB_IMAGE: &str = r#"{"op":"mcm","id":2,"initialClk":"oQKrLpWWHb0FqgWrmREA","clk":"AAAAAAAA","conflateMs":0,"heartbeatMs":5000,"pt":1721045421337,"ct":"SUB_IMAGE","mc":[{"id":"1.230312812","marketDefinition":{"bspMarket":false,"turnInPlayEnabled":true,"persistenceEnabled":true,"marketBaseRate":5,"eventId":"33406143","eventTypeId":"1","numberOfWinners":1,"bettingType":"ODDS","marketType":"MATCH_ODDS","marketTime":"2024-07-15T19:00:00.000Z","suspendTime":"2024-07-15T19:00:00.000Z","bspReconciled":false,"complete":true,"inPlay":false,"crossMatching":true,"runnersVoidable":false,"numberOfActiveRunners":3,"betDelay":0,"status":"OPEN","runners":[{"status":"ACTIVE","sortPriority":1,"id":47999},{"status":"ACTIVE","sortPriority":2,"id":56323},{"status":"ACTIVE","sortPriority":3,"id":58805}],"regulators":["MR_INT"],"countryCode":"GB","discountAllowed":true,"timezone":"GMT","openDate":"2024-07-15T19:00:00.000Z","version":5918153474,"priceLadderDefinition":{"type":"CLASSIC"}},"rc":[{"atb":[[1.95,12.5],[1.94,110.03]],"atl":[[1.96,40.13],[1.97,3.12]],"trd":[[1.95,2],[1.96,104.55]],"batb":[[0,1.95,12.5],[1,1.94,110.03]],"batl":[[0,1.96,40.13]],"ltp":1.96,"tv":106.55,"id":47999}],"img":true,"tv":106.55}]}"#;
+
+    const DELTA: &str = r#"{"op":"mcm","id":2,"clk":"AKMBAKwBAIUB","pt":1721045421712,"mc":[{"id":"1.230312812","rc":[{"atl":[[1.96,0]],"batl":[[0,1.97,3.12]],"id":47999},{"atb":[[5.5,20]],"hc":0,"id":56323}],"con":true}]}"#;
+
+    const HEARTBEAT: &str =
+        r#"{"op":"mcm","id":2,"clk":"AKMBAKwBAIUB","pt":1721045426712,"ct":"HEARTBEAT"}"#;
+
+    const SP: &str = r#"{"op":"mcm","id":3,"clk":"ALgBAJ4BAKcB","pt":1721045500000,"segmentType":"SEG_START","mc":[{"id":"1.230312999","rc":[{"spn":4.2,"spf":4.35,"spb":[[1000,5.5]],"spl":[[1.01,12]],"bdatb":[[0,4.1,2.5]],"bdatl":[[0,4.3,7]],"id":1234}]}]}"#;
+
+    fn parse(frame: &str) -> MarketChangeMessage {
+        match serde_json::from_str(frame).unwrap() {
+            ResponseMessage::Mcm(mcm) => mcm,
+            other => panic!("expected mcm got {:?}", other),
+        }
+    }
+
+    fn round_trip(frame: &str) {
+        let original: ResponseMessage = serde_json::from_str(frame).unwrap();
+        let json = serde_json::to_string(&original).unwrap();
+        let again: ResponseMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(original, again);
+    }
+
+    #[test]
+    fn deserialize_sub_image() {
+        let mcm = parse(SUB_IMAGE);
+        assert_eq!(mcm.ct, Some(ChangeType::SubImage));
+        assert_eq!(mcm.initial_clk.as_deref(), Some("oQKrLpWWHb0FqgWrmREA"));
+        assert_eq!(mcm.heartbeat_ms, Some(5000));
+        let mc = &mcm.mc.unwrap()[0];
+        assert_eq!(mc.img, Some(true));
+        let def = mc.market_definition.as_ref().unwrap();
+        assert_eq!(def.status, MarketStatus::Open);
+        assert_eq!(def.runners.len(), 3);
+        assert_eq!(
+            def.price_ladder_definition.as_ref().unwrap().ladder_type,
+            PriceLadderType::Classic
+        );
+        let rc = &mc.rc.as_ref().unwrap()[0];
+        assert_eq!(rc.id, 47999);
+        assert_eq!(rc.atb.as_ref().unwrap()[1], [1.94, 110.03]);
+        assert_eq!(rc.batl.as_ref().unwrap()[0], [0., 1.96, 40.13]);
+        assert_eq!(rc.ltp, Some(1.96));
+    }
+
+    #[test]
+    fn deserialize_delta() {
+        let mcm = parse(DELTA);
+        assert_eq!(mcm.ct, None);
+        let mc = &mcm.mc.unwrap()[0];
+        assert_eq!(mc.con, Some(true));
+        assert!(mc.market_definition.is_none());
+        let rc = mc.rc.as_ref().unwrap();
+        assert_eq!(rc[0].atl.as_ref().unwrap()[0], [1.96, 0.]);
+        assert_eq!(rc[1].hc, Some(0.));
+    }
+
+    #[test]
+    fn deserialize_heartbeat() {
+        let mcm = parse(HEARTBEAT);
+        assert_eq!(mcm.ct, Some(ChangeType::Heartbeat));
+        assert!(mcm.mc.is_none());
+    }
+
+    #[test]
+    fn deserialize_starting_price() {
+        let mcm = parse(SP);
+        assert_eq!(mcm.segment_type, Some(SegmentType::SegStart));
+        let mc = mcm.mc.unwrap();
+        let rc = &mc[0].rc.as_ref().unwrap()[0];
+        assert_eq!(rc.spn, Some(4.2));
+        assert_eq!(rc.spf, Some(4.35));
+        assert_eq!(rc.spb.as_ref().unwrap()[0], [1000., 5.5]);
+        assert_eq!(rc.bdatl.as_ref().unwrap()[0], [0., 4.3, 7.]);
+    }
+
+    #[test]
+    fn round_trip_frames() {
+        for frame in [SUB_IMAGE, DELTA, HEARTBEAT, SP] {
+            round_trip(frame);
+        }
+    }
+}
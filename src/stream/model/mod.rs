@@ -1,3 +1,9 @@
+mod mcm;
+mod ocm;
+mod rcm;
 mod response;
 
+pub use mcm::*;
+pub use ocm::*;
+pub use rcm::*;
 pub use response::*;
@@ -0,0 +1,267 @@
+// Order Change Message, the state of our own orders from the order stream
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::{ChangeType, PriceSize, SegmentType};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderChangeMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    /// Missing for a plain delta
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ct: Option<ChangeType>,
+    /// Token used to resume the stream from this point
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clk: Option<String>,
+    /// Token used to resume the stream from the initial image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_clk: Option<String>,
+    /// Publish time in milliseconds since epoch
+    pub pt: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflate_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub segment_type: Option<SegmentType>,
+    /// Set to 503 when the stream is serving stale data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oc: Option<Vec<OrderMarketChange>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderMarketChange {
+    /// Market id
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<i64>,
+    /// Set when the market is closed and no more changes will follow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub closed: Option<bool>,
+    /// Replace the cached market instead of applying a delta
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full_image: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orc: Option<Vec<OrderRunnerChange>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderRunnerChange {
+    /// Selection id
+    pub id: i64,
+    /// Handicap, only set on handicap markets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hc: Option<f64>,
+    /// Replace the cached runner instead of applying a delta
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full_image: Option<bool>,
+    /// Unmatched orders, once an order is complete it is sent one last time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uo: Option<Vec<Order>>,
+    /// Matched backs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mb: Option<Vec<PriceSize>>,
+    /// Matched lays
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ml: Option<Vec<PriceSize>>,
+    /// Matched backs and lays partitioned by strategy ref
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smc: Option<HashMap<String, StrategyMatchChange>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StrategyMatchChange {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mb: Option<Vec<PriceSize>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ml: Option<Vec<PriceSize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OrderSide {
+    #[serde(rename = "B")]
+    Back,
+    #[serde(rename = "L")]
+    Lay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderStatus {
+    #[serde(rename = "E")]
+    Executable,
+    #[serde(rename = "EC")]
+    ExecutionComplete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PersistenceType {
+    /// Lapse
+    #[serde(rename = "L")]
+    Lapse,
+    /// Persist
+    #[serde(rename = "P")]
+    Persist,
+    /// Market on close
+    #[serde(rename = "MOC")]
+    MarketOnClose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderType {
+    #[serde(rename = "L")]
+    Limit,
+    #[serde(rename = "LOC")]
+    LimitOnClose,
+    #[serde(rename = "MOC")]
+    MarketOnClose,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Order {
+    /// Bet id
+    pub id: String,
+    /// Price
+    pub p: f64,
+    /// Size
+    pub s: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bsp: Option<f64>,
+    pub side: OrderSide,
+    pub status: OrderStatus,
+    /// Persistence type
+    pub pt: PersistenceType,
+    /// Order type
+    pub ot: OrderType,
+    /// Placed date in milliseconds since epoch
+    pub pd: i64,
+    /// Matched date in milliseconds since epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md: Option<i64>,
+    /// Cancelled date in milliseconds since epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cd: Option<i64>,
+    /// Lapsed date in milliseconds since epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ld: Option<i64>,
+    /// Lapse status reason code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lsrc: Option<String>,
+    /// Average price matched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avp: Option<f64>,
+    /// Size matched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sm: Option<f64>,
+    /// Size remaining
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sr: Option<f64>,
+    /// Size lapsed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sl: Option<f64>,
+    /// Size cancelled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sc: Option<f64>,
+    /// Size voided
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sv: Option<f64>,
+    /// Regulator auth code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rac: Option<String>,
+    /// Regulator code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rc: Option<String>,
+    /// Customer order ref
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rfo: Option<String>,
+    /// Customer strategy ref
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rfs: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ResponseMessage;
+    use super::*;
+
+    const SUB_IMAGE: &str = r#"{"op":"ocm","id":3,"initialClk":"GpOjqP4DHqC3ov4D","clk":"AAAAAAAAAAAAAA==","conflateMs":0,"heartbeatMs":5000,"pt":1721045421337,"ct":"SUB_IMAGE","oc":[{"accountId":1234567,"id":"1.230312812","fullImage":true,"orc":[{"fullImage":true,"id":47999,"hc":0,"uo":[{"id":"335102483001","p":2.02,"s":10,"side":"B","status":"E","pt":"L","ot":"L","pd":1721045400000,"sm":4,"sr":6,"sl":0,"sc":0,"sv":0,"rac":"","rc":"REG_GGC","rfo":"bfg-1","rfs":"scalp"}],"mb":[[2.02,4]],"ml":[[1.98,20.5]],"smc":{"scalp":{"mb":[[2.02,4]]}}}]}]}"#;
+
+    const DELTA: &str = r#"{"op":"ocm","id":3,"clk":"AAAAAAAAAAAAAB==","pt":1721045422000,"oc":[{"id":"1.230312812","orc":[{"id":47999,"uo":[{"id":"335102483001","p":2.02,"s":10,"side":"B","status":"EC","pt":"L","ot":"L","pd":1721045400000,"md":1721045421999,"avp":2.02,"sm":10,"sr":0,"sl":0,"sc":0,"sv":0,"rfs":"scalp"},{"id":"335102483002","p":1.98,"s":5,"side":"L","status":"EC","pt":"P","ot":"L","pd":1721045410000,"cd":1721045421998,"sm":0,"sr":0,"sl":0,"sc":5,"sv":0}],"mb":[[2.02,10]]}]}]}"#;
+
+    const CLOSED: &str = r#"{"op":"ocm","id":3,"clk":"AAAAAAAAAAAAAC==","pt":1721049000000,"oc":[{"id":"1.230312812","closed":true}]}"#;
+
+    fn parse(frame: &str) -> OrderChangeMessage {
+        match serde_json::from_str(frame).unwrap() {
+            ResponseMessage::Ocm(ocm) => ocm,
+            other => panic!("expected ocm got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deserialize_sub_image() {
+        let ocm = parse(SUB_IMAGE);
+        assert_eq!(ocm.ct, Some(ChangeType::SubImage));
+        let oc = &ocm.oc.unwrap()[0];
+        assert_eq!(oc.full_image, Some(true));
+        assert_eq!(oc.account_id, Some(1234567));
+        let orc = &oc.orc.as_ref().unwrap()[0];
+        assert_eq!(orc.full_image, Some(true));
+        assert_eq!(orc.mb.as_ref().unwrap()[0], [2.02, 4.]);
+        assert_eq!(orc.ml.as_ref().unwrap()[0], [1.98, 20.5]);
+        assert_eq!(
+            orc.smc.as_ref().unwrap()["scalp"].mb.as_ref().unwrap()[0],
+            [2.02, 4.]
+        );
+        let order = &orc.uo.as_ref().unwrap()[0];
+        assert_eq!(order.id, "335102483001");
+        assert_eq!(order.side, OrderSide::Back);
+        assert_eq!(order.status, OrderStatus::Executable);
+        assert_eq!(order.pt, PersistenceType::Lapse);
+        assert_eq!(order.ot, OrderType::Limit);
+        assert_eq!(order.sm, Some(4.));
+        assert_eq!(order.sr, Some(6.));
+        assert_eq!(order.rfo.as_deref(), Some("bfg-1"));
+        assert_eq!(order.rfs.as_deref(), Some("scalp"));
+    }
+
+    #[test]
+    fn deserialize_delta() {
+        let ocm = parse(DELTA);
+        assert_eq!(ocm.ct, None);
+        let oc = &ocm.oc.unwrap()[0];
+        assert_eq!(oc.full_image, None);
+        let uo = oc.orc.as_ref().unwrap()[0].uo.as_ref().unwrap();
+        assert_eq!(uo[0].status, OrderStatus::ExecutionComplete);
+        assert_eq!(uo[0].md, Some(1721045421999));
+        assert_eq!(uo[0].avp, Some(2.02));
+        assert_eq!(uo[1].side, OrderSide::Lay);
+        assert_eq!(uo[1].pt, PersistenceType::Persist);
+        assert_eq!(uo[1].cd, Some(1721045421998));
+        assert_eq!(uo[1].sc, Some(5.));
+    }
+
+    #[test]
+    fn deserialize_closed() {
+        let ocm = parse(CLOSED);
+        let oc = &ocm.oc.unwrap()[0];
+        assert_eq!(oc.closed, Some(true));
+        assert!(oc.orc.is_none());
+    }
+
+    #[test]
+    fn round_trip_frames() {
+        for frame in [SUB_IMAGE, DELTA, CLOSED] {
+            let original: ResponseMessage = serde_json::from_str(frame).unwrap();
+            let json = serde_json::to_string(&original).unwrap();
+            let again: ResponseMessage = serde_json::from_str(&json).unwrap();
+            assert_eq!(original, again);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// Race Change Message, Total Performance Data tracking the runners of a race
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RaceChangeMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    /// Token used to resume the stream from this point
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clk: Option<String>,
+    /// Publish time in milliseconds since epoch
+    pub pt: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rc: Option<Vec<RaceChange>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RaceChange {
+    /// Race id, the meeting and race time
+    pub id: String,
+    /// Market id of the win market
+    pub mid: String,
+    /// Race progress
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rpc: Option<RaceProgress>,
+    /// Runner positions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rrc: Option<Vec<RaceRunner>>,
+}
+
+/// Where the race as a whole is, given by the leader
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RaceProgress {
+    /// Feed time in milliseconds since epoch
+    pub ft: i64,
+    /// Gate the leader last passed, like 1f or Finish
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub g: Option<String>,
+    /// Sectional time since the previous gate in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub st: Option<f64>,
+    /// Running time since the start in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rt: Option<f64>,
+    /// Speed of the leader in m/s
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spd: Option<f64>,
+    /// Distance the leader has left to the finish in metres
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prg: Option<f64>,
+    /// Selection ids in running order, leader first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ord: Option<Vec<i64>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RaceRunner {
+    /// Feed time in milliseconds since epoch
+    pub ft: i64,
+    /// Selection id
+    pub id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long: Option<f64>,
+    /// Speed in m/s
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spd: Option<f64>,
+    /// Distance left to the finish in metres
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prg: Option<f64>,
+    /// Stride frequency in strides per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sfq: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ResponseMessage;
+
+    const RCM: &str = r#"{"op":"rcm","id":4,"clk":"AAAAAAAA","pt":1721051720150,"rc":[{"id":"28587288.1650","mid":"1.230312812","rpc":{"ft":1721051720100,"g":"4f","st":12.51,"rt":48.2,"spd":16.9,"prg":804.2,"ord":[47999,12345,67890]},"rrc":[{"ft":1721051720100,"id":47999,"lat":51.4183,"long":-0.4156,"spd":16.9,"prg":804.2,"sfq":2.21},{"ft":1721051720100,"id":12345,"spd":16.7,"prg":806.9}]}]}"#;
+
+    #[test]
+    fn deserialize_race_change() {
+        let ResponseMessage::Rcm(rcm) = serde_json::from_str(RCM).unwrap() else {
+            panic!("expected rcm");
+        };
+        assert_eq!(rcm.id, Some(4));
+        assert_eq!(rcm.pt, 1721051720150);
+        let rc = &rcm.rc.as_ref().unwrap()[0];
+        assert_eq!(rc.id, "28587288.1650");
+        assert_eq!(rc.mid, "1.230312812");
+        let rpc = rc.rpc.as_ref().unwrap();
+        assert_eq!(rpc.g.as_deref(), Some("4f"));
+        assert_eq!(rpc.prg, Some(804.2));
+        assert_eq!(rpc.ord.as_deref(), Some([47999, 12345, 67890].as_slice()));
+        let rrc = rc.rrc.as_ref().unwrap();
+        assert_eq!(rrc[0].sfq, Some(2.21));
+        assert_eq!(rrc[1].lat, None);
+        assert_eq!(rrc[1].prg, Some(806.9));
+
+        let json = serde_json::to_string(&ResponseMessage::Rcm(rcm.clone())).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ResponseMessage>(&json).unwrap(),
+            ResponseMessage::Rcm(rcm)
+        );
+    }
+}
@@ -3,36 +3,126 @@
 // OrderSubscription
 // Heartbeat
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize)]
+use super::{MarketChangeMessage, OrderChangeMessage, RaceChangeMessage};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ConnectionResponse {
-    connection_id: String,
+    pub connection_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum StatusCode {
     Success,
     Failure,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// No app key in the authentication request
+    NoAppKey,
+    /// The app key is not valid
+    InvalidAppKey,
+    /// No session token in the authentication request
+    NoSession,
+    /// The session token has expired or is not valid
+    InvalidSessionInformation,
+    /// The app key is not allowed to use the stream
+    NotAuthorized,
+    InvalidInput,
+    /// The clk or initialClk could not be resumed from
+    InvalidClock,
+    UnexpectedError,
+    /// Nothing was sent on the connection for too long
+    Timeout,
+    /// Too many markets in the subscription
+    SubscriptionLimitExceeded,
+    InvalidRequest,
+    ConnectionFailed,
+    /// Every connection this app key may have is in use
+    MaxConnectionLimitExceeded,
+    /// Requests are being throttled
+    TooManyRequests,
+    /// A code added by betfair after this was written
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Logging in again gives a session token that will work
+    pub fn is_session_error(self) -> bool {
+        matches!(self, Self::NoSession | Self::InvalidSessionInformation)
+    }
+
+    /// Betfair wants us to slow down before connecting again
+    pub fn is_throttled(self) -> bool {
+        matches!(
+            self,
+            Self::TooManyRequests | Self::MaxConnectionLimitExceeded
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StatusResponse {
-    status_code: StatusCode,
-    error_code: Option<String>,
-    error_message: Option<String>,
-    connection_closed: bool,
-    connections_available: Option<usize>,
+    /// Id of the request this answers, missing when betfair closes the connection on its own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    pub status_code: StatusCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_message: Option<String>,
+    pub connection_closed: bool,
+    /// How many more connections the app key may open
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connections_available: Option<usize>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "op", rename_all = "camelCase")]
 pub enum ResponseMessage {
     Connection(ConnectionResponse),
     Status(StatusResponse),
-    Mcm(String),
-    Ocm(String),
+    Mcm(MarketChangeMessage),
+    Ocm(OrderChangeMessage),
+    Rcm(RaceChangeMessage),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(frame: &str) -> StatusResponse {
+        match serde_json::from_str(frame).unwrap() {
+            ResponseMessage::Status(status) => status,
+            other => panic!("expected status got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deserialize_error_codes() {
+        let throttled = status(
+            r#"{"op":"status","id":4,"statusCode":"FAILURE","errorCode":"TOO_MANY_REQUESTS","errorMessage":"slow down","connectionClosed":true,"connectionsAvailable":0}"#,
+        );
+        assert_eq!(throttled.error_code, Some(ErrorCode::TooManyRequests));
+        assert!(throttled.error_code.unwrap().is_throttled());
+        assert_eq!(throttled.connections_available, Some(0));
+
+        let expired = status(
+            r#"{"op":"status","statusCode":"FAILURE","errorCode":"INVALID_SESSION_INFORMATION","connectionClosed":true}"#,
+        );
+        assert!(expired.error_code.unwrap().is_session_error());
+        assert_eq!(expired.id, None);
+
+        let unknown = status(
+            r#"{"op":"status","id":1,"statusCode":"FAILURE","errorCode":"SOMETHING_NEW","connectionClosed":false}"#,
+        );
+        assert_eq!(unknown.error_code, Some(ErrorCode::Unknown));
+    }
 }
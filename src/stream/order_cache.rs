@@ -0,0 +1,330 @@
+// Our orders and what they add up to, built from the order stream
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use super::{
+    market_cache::{by_runner, Clocks},
+    model::{
+        Order, OrderChangeMessage, OrderMarketChange, OrderRunnerChange, OrderStatus, PriceSize,
+    },
+    PriceLadder, RunnerKey,
+};
+
+/// Matched amounts by price
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Matched {
+    pub backs: PriceLadder,
+    pub lays: PriceLadder,
+}
+
+impl Matched {
+    fn update(&mut self, mb: &Option<Vec<PriceSize>>, ml: &Option<Vec<PriceSize>>) {
+        if let Some(mb) = mb {
+            self.backs.update(mb);
+        }
+        if let Some(ml) = ml {
+            self.lays.update(ml);
+        }
+    }
+
+    pub fn position(&self) -> Position {
+        Position::new(self.backs.levels(), self.lays.levels())
+    }
+}
+
+/// What the matched bets on one selection add up to
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Position {
+    /// Total backed
+    pub backed: f64,
+    /// Total laid
+    pub laid: f64,
+    /// Backed less laid
+    pub net_stake: f64,
+    /// Size weighted average of the backs, 0 without any
+    pub average_back_price: f64,
+    /// Size weighted average of the lays, 0 without any
+    pub average_lay_price: f64,
+    /// Profit when the selection wins
+    pub if_win: f64,
+    /// Profit when it loses
+    pub if_lose: f64,
+    /// The most we can lose, 0 when both outcomes are a profit
+    pub liability: f64,
+}
+
+impl Position {
+    fn new(backs: &[PriceSize], lays: &[PriceSize]) -> Self {
+        let total = |levels: &[PriceSize]| levels.iter().map(|[_, size]| size).sum::<f64>();
+        let returns = |levels: &[PriceSize]| {
+            levels
+                .iter()
+                .map(|[price, size]| (price - 1.) * size)
+                .sum::<f64>()
+        };
+        let average = |levels: &[PriceSize], total: f64| {
+            if total == 0. {
+                return 0.;
+            }
+            levels.iter().map(|[price, size]| price * size).sum::<f64>() / total
+        };
+        let (backed, laid) = (total(backs), total(lays));
+        let if_win = returns(backs) - returns(lays);
+        let if_lose = laid - backed;
+        Self {
+            backed,
+            laid,
+            net_stake: backed - laid,
+            average_back_price: average(backs, backed),
+            average_lay_price: average(lays, laid),
+            if_win,
+            if_lose,
+            liability: -if_win.min(if_lose).min(0.),
+        }
+    }
+}
+
+/// Our orders on one runner
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunnerOrders {
+    /// Orders still waiting to be matched, by bet id
+    pub unmatched: BTreeMap<String, Order>,
+    pub matched: Matched,
+    /// Matched amounts by customer strategy ref
+    pub strategies: BTreeMap<String, Matched>,
+}
+
+impl RunnerOrders {
+    pub fn position(&self) -> Position {
+        self.matched.position()
+    }
+
+    // The orders that changed
+    fn update(&mut self, change: &OrderRunnerChange) -> Vec<Order> {
+        for order in change.uo.iter().flatten() {
+            match order.status {
+                OrderStatus::Executable => {
+                    self.unmatched.insert(order.id.clone(), order.clone());
+                }
+                // Sent one last time when done
+                OrderStatus::ExecutionComplete => {
+                    self.unmatched.remove(&order.id);
+                }
+            }
+        }
+        self.matched.update(&change.mb, &change.ml);
+        for (strategy, matched) in change.smc.iter().flatten() {
+            self.strategies
+                .entry(strategy.clone())
+                .or_default()
+                .update(&matched.mb, &matched.ml);
+        }
+        change.uo.clone().unwrap_or_default()
+    }
+}
+
+/// Our orders on one market
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketOrders {
+    pub market_id: String,
+    #[serde(with = "by_runner")]
+    pub runners: BTreeMap<RunnerKey, RunnerOrders>,
+    /// No more changes will come for the market
+    pub closed: bool,
+}
+
+impl MarketOrders {
+    pub fn new(market_id: &str) -> Self {
+        Self {
+            market_id: String::from(market_id),
+            runners: BTreeMap::new(),
+            closed: false,
+        }
+    }
+
+    pub fn runner(&self, selection_id: i64, handicap: f64) -> Option<&RunnerOrders> {
+        self.runners.get(&RunnerKey::new(selection_id, handicap))
+    }
+
+    fn update(&mut self, change: &OrderMarketChange, notifications: &mut Vec<OrderNotification>) {
+        for runner in change.orc.iter().flatten() {
+            let key = RunnerKey::new(runner.id, runner.hc.unwrap_or_default());
+            if runner.full_image == Some(true) {
+                self.runners.remove(&key);
+            }
+            let orders = self.runners.entry(key).or_default();
+            for order in orders.update(runner) {
+                notifications.push(OrderNotification::Order {
+                    market_id: self.market_id.clone(),
+                    runner: key,
+                    order: Box::new(order),
+                });
+            }
+            if runner.mb.is_some() || runner.ml.is_some() || runner.full_image == Some(true) {
+                notifications.push(OrderNotification::Position {
+                    market_id: self.market_id.clone(),
+                    runner: key,
+                    position: orders.position(),
+                });
+            }
+        }
+        if change.closed == Some(true) {
+            self.closed = true;
+            notifications.push(OrderNotification::MarketClosed(self.market_id.clone()));
+        }
+    }
+}
+
+/// What changed in the cache, for whoever follows our orders
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderNotification {
+    /// An order was placed or changed, a complete one is no longer unmatched
+    Order {
+        market_id: String,
+        runner: RunnerKey,
+        order: Box<Order>,
+    },
+    /// Something got matched on the runner
+    Position {
+        market_id: String,
+        runner: RunnerKey,
+        position: Position,
+    },
+    MarketClosed(String),
+}
+
+/// Our orders on every market, by market id
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderCache {
+    markets: HashMap<String, MarketOrders>,
+    // Where the order stream is at, to resume from
+    #[serde(flatten)]
+    clocks: Clocks,
+}
+
+impl OrderCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Apply a whole message, segments have to be put together first
+    pub fn update(&mut self, ocm: &OrderChangeMessage) -> Vec<OrderNotification> {
+        self.clocks
+            .update(ocm.id, ocm.ct, &ocm.initial_clk, &ocm.clk);
+        let mut notifications = Vec::new();
+        for change in ocm.oc.iter().flatten() {
+            // A full image replaces whatever we had for the market
+            if change.full_image == Some(true) {
+                self.markets.remove(&change.id);
+            }
+            self.markets
+                .entry(change.id.clone())
+                .or_insert_with(|| MarketOrders::new(&change.id))
+                .update(change, &mut notifications);
+        }
+        notifications
+    }
+
+    pub fn market(&self, market_id: &str) -> Option<&MarketOrders> {
+        self.markets.get(market_id)
+    }
+
+    /// The initial clk and clk of the last message applied, to resume the
+    /// order subscription from where the cache is
+    pub fn clocks(&self) -> Option<(&str, &str)> {
+        self.clocks.get()
+    }
+
+    pub fn markets(&self) -> impl Iterator<Item = &MarketOrders> {
+        self.markets.values()
+    }
+
+    /// Forget a market, once it has closed
+    pub fn remove(&mut self, market_id: &str) -> Option<MarketOrders> {
+        self.markets.remove(market_id)
+    }
+
+    /// Keep only the markets `keep` is true for
+    pub fn retain(&mut self, mut keep: impl FnMut(&MarketOrders) -> bool) {
+        self.markets.retain(|_, market| keep(market));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ocm(frame: &str) -> OrderChangeMessage {
+        serde_json::from_str(frame).unwrap()
+    }
+
+    const IMAGE: &str = r#"{"pt":1,"oc":[{"id":"1.1","fullImage":true,"orc":[{"id":7,"fullImage":true,"uo":[{"id":"b1","p":2.5,"s":10,"side":"B","status":"E","pt":"L","ot":"L","pd":1,"sm":4,"sr":6}],"mb":[[2.5,4],[3,6]],"ml":[[2,5]],"smc":{"scalp":{"mb":[[2.5,4]]}}}]}]}"#;
+
+    #[test]
+    fn position_from_matched_bets() {
+        // Backed 4 at 2.5 and 6 at 3, laid 5 at 2
+        let position = Position::new(&[[2.5, 4.], [3., 6.]], &[[2., 5.]]);
+        assert_eq!(position.backed, 10.);
+        assert_eq!(position.net_stake, 5.);
+        assert_eq!(position.average_back_price, 2.8);
+        assert_eq!(position.average_lay_price, 2.);
+        assert_eq!(position.if_win, 6. + 12. - 5.);
+        assert_eq!(position.if_lose, -5.);
+        assert_eq!(position.liability, 5.);
+
+        // Laid only, the liability is what we pay out on a win
+        let position = Position::new(&[], &[[4., 10.]]);
+        assert_eq!((position.if_win, position.liability), (-30., 30.));
+        assert_eq!(Position::new(&[], &[]), Position::default());
+    }
+
+    #[test]
+    fn apply_images_and_deltas() {
+        let mut sut = OrderCache::new();
+        let notifications = sut.update(&ocm(IMAGE));
+        assert!(
+            matches!(&notifications[0], OrderNotification::Order { order, .. } if order.id == "b1")
+        );
+        let OrderNotification::Position { position, .. } = notifications[1] else {
+            panic!("expected the position");
+        };
+        assert_eq!(position.net_stake, 5.);
+        let runner = sut.market("1.1").unwrap().runner(7, 0.).unwrap();
+        assert_eq!(runner.unmatched.len(), 1);
+        assert_eq!(runner.strategies["scalp"].backs.levels(), [[2.5, 4.]]);
+
+        // The rest of b1 gets matched, c1 is placed on another runner
+        let notifications = sut.update(&ocm(
+            r#"{"pt":2,"oc":[{"id":"1.1","orc":[{"id":7,"uo":[{"id":"b1","p":2.5,"s":10,"side":"B","status":"EC","pt":"L","ot":"L","pd":1,"sm":10,"sr":0}],"mb":[[2.5,10]]},{"id":8,"uo":[{"id":"c1","p":5,"s":2,"side":"L","status":"E","pt":"P","ot":"L","pd":2,"sr":2}]}]}]}"#,
+        ));
+        assert_eq!(notifications.len(), 3);
+        let market = sut.market("1.1").unwrap();
+        let runner = market.runner(7, 0.).unwrap();
+        assert!(runner.unmatched.is_empty());
+        assert_eq!(runner.position().backed, 16.);
+        assert_eq!(market.runner(8, 0.).unwrap().unmatched["c1"].p, 5.);
+
+        // A full image of the runner throws away what it doesn't repeat
+        sut.update(&ocm(
+            r#"{"pt":3,"oc":[{"id":"1.1","orc":[{"id":7,"fullImage":true,"ml":[[2,5]]}]}]}"#,
+        ));
+        let runner = sut.market("1.1").unwrap().runner(7, 0.).unwrap();
+        assert!(runner.matched.backs.is_empty());
+        assert!(runner.strategies.is_empty());
+        assert!(sut.market("1.1").unwrap().runner(8, 0.).is_some());
+
+        // And a full image of the market drops the other runners
+        sut.update(&ocm(IMAGE));
+        assert!(sut.market("1.1").unwrap().runner(8, 0.).is_none());
+
+        let notifications = sut.update(&ocm(r#"{"pt":4,"oc":[{"id":"1.1","closed":true}]}"#));
+        assert_eq!(
+            notifications,
+            [OrderNotification::MarketClosed(String::from("1.1"))]
+        );
+        assert!(sut.market("1.1").unwrap().closed);
+    }
+}
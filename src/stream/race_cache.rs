@@ -0,0 +1,122 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::model::{RaceChangeMessage, RaceProgress, RaceRunner};
+
+/// The latest tracking data of one race
+#[derive(Debug, Clone, PartialEq)]
+pub struct Race {
+    pub race_id: String,
+    pub market_id: String,
+    pub progress: Option<RaceProgress>,
+    /// Latest position of each runner by selection id
+    pub runners: BTreeMap<i64, RaceRunner>,
+}
+
+impl Race {
+    fn new(race_id: &str, market_id: &str) -> Self {
+        Self {
+            race_id: String::from(race_id),
+            market_id: String::from(market_id),
+            progress: None,
+            runners: BTreeMap::new(),
+        }
+    }
+
+    /// Runners in running order, leader first. Uses the order betfair sends
+    /// and falls back to the distance left for runners it doesn't cover.
+    pub fn positions(&self) -> Vec<&RaceRunner> {
+        let order = self
+            .progress
+            .as_ref()
+            .and_then(|progress| progress.ord.as_deref())
+            .unwrap_or_default();
+        let mut positions: Vec<&RaceRunner> = order
+            .iter()
+            .filter_map(|selection_id| self.runners.get(selection_id))
+            .collect();
+        let mut rest: Vec<&RaceRunner> = self
+            .runners
+            .values()
+            .filter(|runner| !order.contains(&runner.id))
+            .collect();
+        rest.sort_by(|a, b| {
+            let distance = |runner: &RaceRunner| runner.prg.unwrap_or(f64::MAX);
+            distance(a).total_cmp(&distance(b))
+        });
+        positions.extend(rest);
+        positions
+    }
+}
+
+/// Tracking data of every race on the stream, by the id of its win market
+#[derive(Debug, Default)]
+pub struct RaceCache {
+    races: HashMap<String, Race>,
+}
+
+impl RaceCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Apply a change, every update carries whole values so newer ones replace older
+    pub fn update(&mut self, rcm: &RaceChangeMessage) {
+        for change in rcm.rc.iter().flatten() {
+            let race = self
+                .races
+                .entry(change.mid.clone())
+                .or_insert_with(|| Race::new(&change.id, &change.mid));
+            if let Some(progress) = change.rpc.as_ref() {
+                race.progress = Some(progress.clone());
+            }
+            for runner in change.rrc.iter().flatten() {
+                race.runners.insert(runner.id, runner.clone());
+            }
+        }
+    }
+
+    pub fn race(&self, market_id: &str) -> Option<&Race> {
+        self.races.get(market_id)
+    }
+
+    /// Forget a race, once its market has closed
+    pub fn remove(&mut self, market_id: &str) -> Option<Race> {
+        self.races.remove(market_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rcm(frame: &str) -> RaceChangeMessage {
+        serde_json::from_str(frame).unwrap()
+    }
+
+    #[test]
+    fn keep_latest_positions_by_market() {
+        let mut sut = RaceCache::new();
+        sut.update(&rcm(
+            r#"{"pt":1,"rc":[{"id":"r.1","mid":"1.1","rrc":[{"ft":1,"id":7,"prg":1000},{"ft":1,"id":8,"prg":998},{"ft":1,"id":9,"prg":1003}]}]}"#,
+        ));
+        let race = sut.race("1.1").unwrap();
+        assert_eq!(race.race_id, "r.1");
+        // No running order yet, closest to the finish leads
+        let ids: Vec<i64> = race.positions().iter().map(|runner| runner.id).collect();
+        assert_eq!(ids, [8, 7, 9]);
+
+        // Runners not in this update keep their last position
+        sut.update(&rcm(
+            r#"{"pt":2,"rc":[{"id":"r.1","mid":"1.1","rpc":{"ft":2,"prg":900,"ord":[7,8]},"rrc":[{"ft":2,"id":7,"prg":900,"spd":17.5}]},{"id":"r.2","mid":"1.2"}]}"#,
+        ));
+        let race = sut.race("1.1").unwrap();
+        assert_eq!(race.runners[&7].spd, Some(17.5));
+        assert_eq!(race.runners[&8].prg, Some(998.));
+        let ids: Vec<i64> = race.positions().iter().map(|runner| runner.id).collect();
+        assert_eq!(ids, [7, 8, 9]);
+        assert!(sut.race("1.2").is_some());
+
+        assert!(sut.remove("1.1").is_some());
+        assert!(sut.race("1.1").is_none());
+    }
+}
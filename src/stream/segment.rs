@@ -0,0 +1,175 @@
+use super::model::{MarketChangeMessage, OrderChangeMessage, ResponseMessage, SegmentType};
+
+// Merging in the last segment leaves a whole message
+fn complete(segment_type: Option<SegmentType>) -> Option<SegmentType> {
+    segment_type.filter(|t| *t != SegmentType::SegEnd)
+}
+
+/// A change message that betfair may split into segments
+trait Segmented {
+    fn segment_type(&self) -> Option<SegmentType>;
+    /// Append the next segment to this one
+    fn merge(&mut self, next: Self);
+}
+
+impl Segmented for MarketChangeMessage {
+    fn segment_type(&self) -> Option<SegmentType> {
+        self.segment_type
+    }
+
+    fn merge(&mut self, next: Self) {
+        if let Some(mc) = next.mc {
+            self.mc.get_or_insert_with(Vec::new).extend(mc);
+        }
+        self.pt = next.pt;
+        self.ct = next.ct.or(self.ct);
+        self.clk = next.clk.or(self.clk.take());
+        self.initial_clk = next.initial_clk.or(self.initial_clk.take());
+        self.heartbeat_ms = next.heartbeat_ms.or(self.heartbeat_ms);
+        self.conflate_ms = next.conflate_ms.or(self.conflate_ms);
+        self.status = next.status.or(self.status);
+        self.segment_type = complete(next.segment_type);
+    }
+}
+
+impl Segmented for OrderChangeMessage {
+    fn segment_type(&self) -> Option<SegmentType> {
+        self.segment_type
+    }
+
+    fn merge(&mut self, next: Self) {
+        if let Some(oc) = next.oc {
+            self.oc.get_or_insert_with(Vec::new).extend(oc);
+        }
+        self.pt = next.pt;
+        self.ct = next.ct.or(self.ct);
+        self.clk = next.clk.or(self.clk.take());
+        self.initial_clk = next.initial_clk.or(self.initial_clk.take());
+        self.heartbeat_ms = next.heartbeat_ms.or(self.heartbeat_ms);
+        self.conflate_ms = next.conflate_ms.or(self.conflate_ms);
+        self.status = next.status.or(self.status);
+        self.segment_type = complete(next.segment_type);
+    }
+}
+
+// Returns the complete message once the last segment is in
+fn assemble<T: Segmented>(buffer: &mut Option<T>, message: T) -> Option<T> {
+    match message.segment_type() {
+        None => Some(message),
+        Some(SegmentType::SegStart) => {
+            *buffer = Some(message);
+            None
+        }
+        Some(segment_type) => {
+            // A segment without its start, e.g. after a reconnect, is useless on its own
+            let mut assembled = buffer.take()?;
+            assembled.merge(message);
+            if segment_type == SegmentType::SegEnd {
+                Some(assembled)
+            } else {
+                *buffer = Some(assembled);
+                None
+            }
+        }
+    }
+}
+
+/// Buffers SEG_START/SEG/SEG_END frames so consumers only see whole change
+/// messages and a cache never applies half an image.
+#[derive(Debug, Default)]
+pub struct SegmentAssembler {
+    market: Option<MarketChangeMessage>,
+    order: Option<OrderChangeMessage>,
+}
+
+impl SegmentAssembler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// `None` while a segmented message is still being collected
+    pub fn push(&mut self, message: ResponseMessage) -> Option<ResponseMessage> {
+        match message {
+            ResponseMessage::Mcm(mcm) => assemble(&mut self.market, mcm).map(ResponseMessage::Mcm),
+            ResponseMessage::Ocm(ocm) => assemble(&mut self.order, ocm).map(ResponseMessage::Ocm),
+            other => Some(other),
+        }
+    }
+
+    /// Drop anything half received, the segments won't continue on a new connection
+    pub fn clear(&mut self) {
+        self.market = None;
+        self.order = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::model::ChangeType;
+
+    fn mcm(frame: &str) -> ResponseMessage {
+        serde_json::from_str(frame).unwrap()
+    }
+
+    #[test]
+    fn assemble_segmented_image() {
+        let mut sut = SegmentAssembler::new();
+        let start = mcm(
+            r#"{"op":"mcm","id":1,"initialClk":"init","pt":1,"ct":"SUB_IMAGE","segmentType":"SEG_START","mc":[{"id":"1.1","img":true}]}"#,
+        );
+        let seg =
+            mcm(r#"{"op":"mcm","id":1,"pt":2,"segmentType":"SEG","mc":[{"id":"1.2","img":true}]}"#);
+        let end = mcm(
+            r#"{"op":"mcm","id":1,"clk":"end","pt":3,"segmentType":"SEG_END","mc":[{"id":"1.3","img":true}]}"#,
+        );
+
+        assert!(sut.push(start).is_none());
+        assert!(sut.push(seg).is_none());
+        let Some(ResponseMessage::Mcm(assembled)) = sut.push(end) else {
+            panic!("expected an assembled mcm");
+        };
+        let ids: Vec<&str> = assembled
+            .mc
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|mc| mc.id.as_str())
+            .collect();
+        assert_eq!(ids, ["1.1", "1.2", "1.3"]);
+        assert_eq!(assembled.ct, Some(ChangeType::SubImage));
+        assert_eq!(assembled.initial_clk.as_deref(), Some("init"));
+        assert_eq!(assembled.clk.as_deref(), Some("end"));
+        assert_eq!(assembled.pt, 3);
+        assert_eq!(assembled.segment_type, None);
+    }
+
+    #[test]
+    fn pass_through_unsegmented() {
+        let mut sut = SegmentAssembler::new();
+        let delta = mcm(r#"{"op":"mcm","id":1,"clk":"c","pt":1,"mc":[{"id":"1.1"}]}"#);
+        assert_eq!(sut.push(delta.clone()), Some(delta));
+    }
+
+    #[test]
+    fn drop_segments_without_start() {
+        let mut sut = SegmentAssembler::new();
+        let start = mcm(r#"{"op":"mcm","id":1,"pt":1,"segmentType":"SEG_START","mc":[]}"#);
+        let end = mcm(r#"{"op":"mcm","id":1,"pt":2,"segmentType":"SEG_END","mc":[]}"#);
+        assert!(sut.push(end.clone()).is_none());
+        assert!(sut.push(start).is_none());
+        sut.clear();
+        assert!(sut.push(end).is_none());
+    }
+
+    #[test]
+    fn market_and_order_segments_are_independent() {
+        let mut sut = SegmentAssembler::new();
+        let start = mcm(r#"{"op":"mcm","id":1,"pt":1,"segmentType":"SEG_START","mc":[]}"#);
+        let ocm = mcm(r#"{"op":"ocm","id":2,"pt":1,"oc":[]}"#);
+        let end = mcm(r#"{"op":"mcm","id":1,"pt":2,"segmentType":"SEG_END","mc":[]}"#);
+        assert!(sut.push(start).is_none());
+        assert_eq!(sut.push(ocm.clone()), Some(ocm));
+        assert!(sut.push(end).is_some());
+    }
+}
@@ -0,0 +1,1337 @@
+use color_eyre::eyre::{self, WrapErr};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::{
+    model::{ErrorCode, ResponseMessage, StatusCode, StatusResponse},
+    AuthenticationMessage, HeartbeatMessage, LatencySnapshot, LinesCodec,
+    MarketSubscriptionMessage, OrderSubscriptionMessage, ParseError, RaceSubscriptionMessage,
+    SegmentAssembler, StreamEndpoint, Telemetry,
+};
+
+// How long the reader blocks on the socket before it checks for requests to send
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// Least we wait after betfair says we are making too many requests
+const THROTTLED_BACKOFF: Duration = Duration::from_secs(10);
+// Betfair's heartbeat when the subscription doesn't ask for one
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(5);
+// How often the latency is reported while frames arrive
+const LATENCY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Numbers the sessions, so events on a shared channel tell where they came from
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(1);
+
+/// Logs in again and returns the new session token
+pub type RefreshSession = Arc<dyn Fn() -> eyre::Result<String> + Send + Sync>;
+
+/// Tuning for a stream session
+#[derive(Clone)]
+pub struct SessionConfig {
+    pub endpoint: StreamEndpoint,
+    /// Heartbeat intervals without a single frame before the connection is
+    /// considered stale and replaced
+    pub stale_heartbeats: u32,
+    /// Warn when betfair says no more than this many connections are left
+    pub low_connections: usize,
+    /// Longest wait for each answer while connecting and authenticating
+    pub handshake_timeout: Duration,
+    /// Called when betfair rejects the session token, without it the session
+    /// keeps reconnecting with the token it was given
+    pub refresh_session: Option<RefreshSession>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: StreamEndpoint::default(),
+            stale_heartbeats: 3,
+            low_connections: 2,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            refresh_session: None,
+        }
+    }
+}
+
+impl fmt::Debug for SessionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionConfig")
+            .field("endpoint", &self.endpoint)
+            .field("stale_heartbeats", &self.stale_heartbeats)
+            .field("low_connections", &self.low_connections)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("refresh_session", &self.refresh_session.is_some())
+            .finish()
+    }
+}
+
+/// What the stream thread reports back to whoever owns the session
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// Read on the session numbered `connection`, see `StreamSession::connection`
+    Message {
+        connection: usize,
+        message: ResponseMessage,
+    },
+    /// Nothing arrived for several heartbeat intervals, the connection is replaced
+    Stale { silent_for: Duration },
+    /// The connection dropped, a new one is attempted after `retry_in`
+    Disconnected {
+        reason: String,
+        attempt: u32,
+        retry_in: Duration,
+    },
+    /// Betfair says the app key is close to its connection limit
+    ConnectionsLow { available: usize },
+    /// A frame that could not be read was skipped
+    Unparsed { frame: String, reason: String },
+    /// Latency over the last frames, sent every second while frames arrive.
+    /// `connection` is the session it was measured on.
+    Latency {
+        connection: usize,
+        latency: LatencySnapshot,
+    },
+    /// A connection `SubscriptionManager` opened in the background is ready,
+    /// `sync` again to put markets on it
+    Opened { connection: usize },
+    /// Opening a connection in the background failed, the next `sync` says why
+    /// and tries again
+    OpenFailed(String),
+    /// The session is gone and the thread has stopped
+    Closed(String),
+}
+
+// A failure status that ended the connection
+#[derive(Debug)]
+struct StatusError(StatusResponse);
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "betfair closed the connection: {:?} {}",
+            self.0.error_code,
+            self.0.error_message.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+// Throttling gets a longer pause than a dropped connection
+fn backoff(error: &eyre::Report, retry_in: Duration) -> Duration {
+    match error.downcast_ref::<StatusError>() {
+        Some(StatusError(status)) if status.error_code.is_some_and(ErrorCode::is_throttled) => {
+            retry_in.max(THROTTLED_BACKOFF)
+        }
+        _ => retry_in,
+    }
+}
+
+// Where the status answering a request is sent
+type Reply = Sender<StatusResponse>;
+
+enum Request {
+    /// True to resume from the clocks of the current market subscription
+    Markets(Box<MarketSubscriptionMessage>, Reply, bool),
+    Orders(OrderSubscriptionMessage, Reply),
+    Races(Reply),
+    Heartbeat(Reply),
+    Close,
+}
+
+/// The status betfair sends back for one request
+#[derive(Debug)]
+pub struct PendingStatus {
+    status: Receiver<StatusResponse>,
+}
+
+impl PendingStatus {
+    /// Block until the status arrives. Fails if the connection drops first,
+    /// a subscription is still resent on the new connection.
+    pub fn wait(self, timeout: Duration) -> eyre::Result<StatusResponse> {
+        self.status.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => {
+                eyre::eyre!("no status within {:.1}s", timeout.as_secs_f32())
+            }
+            RecvTimeoutError::Disconnected => {
+                eyre::eyre!("connection dropped before the status arrived")
+            }
+        })
+    }
+}
+
+/// A stream connection owned by its own thread. Everything read from betfair
+/// is pushed as a `StreamEvent` on the channel given to `connect`. When the
+/// connection drops it is reopened and the subscriptions are resumed from the
+/// last seen clocks so betfair only sends what was missed.
+pub struct StreamSession {
+    connection: usize,
+    requests: Sender<Request>,
+    handle: Option<JoinHandle<()>>,
+    telemetry: Arc<Mutex<Telemetry>>,
+}
+
+impl StreamSession {
+    pub fn connect(
+        app_key: &str,
+        session_token: &str,
+        events: Sender<StreamEvent>,
+    ) -> eyre::Result<Self> {
+        Self::connect_with(SessionConfig::default(), app_key, session_token, events)
+    }
+
+    /// Connect and authenticate on the calling thread so a bad login fails
+    /// here, then hand the socket over to the stream thread.
+    pub fn connect_with(
+        config: SessionConfig,
+        app_key: &str,
+        session_token: &str,
+        events: Sender<StreamEvent>,
+    ) -> eyre::Result<Self> {
+        let (requests, rx) = mpsc::channel();
+        let telemetry = Arc::new(Mutex::new(Telemetry::new()));
+        let connection = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        let mut worker = Worker {
+            connection,
+            config,
+            app_key: String::from(app_key),
+            session_token: String::from(session_token),
+            requests: rx,
+            events,
+            markets: None,
+            orders: None,
+            races: None,
+            segments: SegmentAssembler::new(),
+            waiting: HashMap::new(),
+            session_expired: false,
+            telemetry: telemetry.clone(),
+        };
+        let codec = match worker.open() {
+            // The token may have expired since login, a refreshed one gets another go
+            Err(_) if worker.session_expired && worker.config.refresh_session.is_some() => {
+                worker.open()?
+            }
+            result => result?,
+        };
+        let handle = thread::Builder::new()
+            .name(String::from("bfg-stream"))
+            .spawn(move || worker.run(codec))?;
+        Ok(Self {
+            connection,
+            requests,
+            handle: Some(handle),
+            telemetry,
+        })
+    }
+
+    /// Tells this session's events apart from those of other sessions
+    pub fn connection(&self) -> usize {
+        self.connection
+    }
+
+    /// How far behind betfair the frames read so far arrived
+    pub fn latency(&self) -> LatencySnapshot {
+        self.telemetry.lock().unwrap().snapshot()
+    }
+
+    /// The status response is also sent as a `StreamEvent` like everything else.
+    pub fn subscribe_markets(
+        &self,
+        subscription: MarketSubscriptionMessage,
+    ) -> eyre::Result<PendingStatus> {
+        self.request(|reply| Request::Markets(Box::new(subscription), reply, false))
+    }
+
+    /// Replace the market subscription, resuming from the clocks of the current
+    /// one so markets that stay only get deltas and new markets get an image.
+    pub fn resume_markets(
+        &self,
+        subscription: MarketSubscriptionMessage,
+    ) -> eyre::Result<PendingStatus> {
+        self.request(|reply| Request::Markets(Box::new(subscription), reply, true))
+    }
+
+    /// The status response is also sent as a `StreamEvent` like everything else.
+    pub fn subscribe_orders(
+        &self,
+        subscription: OrderSubscriptionMessage,
+    ) -> eyre::Result<PendingStatus> {
+        self.request(|reply| Request::Orders(subscription, reply))
+    }
+
+    /// Race positions of every race with tracking data, sent as `Rcm` messages
+    pub fn subscribe_races(&self) -> eyre::Result<PendingStatus> {
+        self.request(Request::Races)
+    }
+
+    /// Ask betfair for a sign of life
+    pub fn heartbeat(&self) -> eyre::Result<PendingStatus> {
+        self.request(Request::Heartbeat)
+    }
+
+    fn request(&self, request: impl FnOnce(Reply) -> Request) -> eyre::Result<PendingStatus> {
+        let (reply, status) = mpsc::channel();
+        self.requests
+            .send(request(reply))
+            .map_err(|_| eyre::eyre!("stream thread has stopped"))?;
+        Ok(PendingStatus { status })
+    }
+}
+
+impl Drop for StreamSession {
+    fn drop(&mut self) {
+        let _ = self.requests.send(Request::Close);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// An active subscription and how far into it we have read
+struct Subscription<T> {
+    message: T,
+    // The request it was last sent as on the current connection
+    id: Option<usize>,
+    initial_clk: Option<String>,
+    clk: Option<String>,
+    heartbeat: Option<Duration>,
+    // Whoever waits for the status, until the subscription is sent
+    reply: Option<Reply>,
+}
+
+impl<T> Subscription<T> {
+    fn new(message: T, heartbeat_ms: Option<u64>, reply: Option<Reply>) -> Self {
+        Self {
+            message,
+            id: None,
+            initial_clk: None,
+            clk: None,
+            heartbeat: heartbeat_ms.map(Duration::from_millis),
+            reply,
+        }
+    }
+
+    // Frames of a subscription this one replaced may still be in flight,
+    // their clocks would be resumed from on the next reconnect
+    fn update(
+        &mut self,
+        id: Option<usize>,
+        initial_clk: &Option<String>,
+        clk: &Option<String>,
+        heartbeat_ms: Option<u64>,
+    ) {
+        if id.is_none() || id != self.id {
+            return;
+        }
+        if initial_clk.is_some() {
+            self.initial_clk.clone_from(initial_clk);
+        }
+        if clk.is_some() {
+            self.clk.clone_from(clk);
+        }
+        // The image tells us the heartbeat betfair actually uses
+        if let Some(heartbeat_ms) = heartbeat_ms {
+            self.heartbeat = Some(Duration::from_millis(heartbeat_ms));
+        }
+    }
+
+    fn clocks(&self) -> Option<(&str, &str)> {
+        Some((self.initial_clk.as_deref()?, self.clk.as_deref()?))
+    }
+}
+
+// Subscription messages that can pick up from earlier clocks
+trait Resume: Clone {
+    fn resume(self, initial_clk: &str, clk: &str) -> Self;
+
+    // The clocks the message itself resumes from, when warm started
+    fn clocks(&self) -> (Option<String>, Option<String>);
+}
+
+impl Resume for MarketSubscriptionMessage {
+    fn resume(self, initial_clk: &str, clk: &str) -> Self {
+        MarketSubscriptionMessage::resume(self, initial_clk, clk)
+    }
+
+    fn clocks(&self) -> (Option<String>, Option<String>) {
+        (self.initial_clk.clone(), self.clk.clone())
+    }
+}
+
+impl Resume for OrderSubscriptionMessage {
+    fn resume(self, initial_clk: &str, clk: &str) -> Self {
+        OrderSubscriptionMessage::resume(self, initial_clk, clk)
+    }
+
+    fn clocks(&self) -> (Option<String>, Option<String>) {
+        (self.initial_clk.clone(), self.clk.clone())
+    }
+}
+
+impl<T: Resume> Subscription<T> {
+    // A reconnect before betfair sends new clocks resumes from the message's own
+    fn resuming(message: T, heartbeat_ms: Option<u64>, reply: Option<Reply>) -> Self {
+        let (initial_clk, clk) = message.clocks();
+        Self {
+            initial_clk,
+            clk,
+            ..Self::new(message, heartbeat_ms, reply)
+        }
+    }
+
+    // The message to send, resuming if we have clocks
+    fn resumed(&self) -> T {
+        match self.clocks() {
+            Some((initial_clk, clk)) => self.message.clone().resume(initial_clk, clk),
+            None => self.message.clone(),
+        }
+    }
+}
+
+struct Worker {
+    connection: usize,
+    config: SessionConfig,
+    app_key: String,
+    session_token: String,
+    requests: Receiver<Request>,
+    events: Sender<StreamEvent>,
+    markets: Option<Subscription<MarketSubscriptionMessage>>,
+    orders: Option<Subscription<OrderSubscriptionMessage>>,
+    // Races have no clocks to resume from, the subscription is just sent again
+    races: Option<Subscription<RaceSubscriptionMessage>>,
+    segments: SegmentAssembler,
+    // Requests sent on the current connection waiting for their status, by id
+    waiting: HashMap<usize, Reply>,
+    // Betfair rejected the token, get a new one before authenticating again
+    session_expired: bool,
+    telemetry: Arc<Mutex<Telemetry>>,
+}
+
+impl Worker {
+    fn run(mut self, mut codec: LinesCodec) {
+        loop {
+            let error = match self.pump(&mut codec) {
+                Ok(()) => break,
+                Err(e) => e,
+            };
+            match self.reconnect(error) {
+                Some(reconnected) => codec = reconnected,
+                None => break,
+            }
+        }
+        let _ = self
+            .events
+            .send(StreamEvent::Closed(String::from("session closed")));
+    }
+
+    // Shuffle requests and messages until the connection fails, Ok means we were told to stop
+    fn pump(&mut self, codec: &mut LinesCodec) -> eyre::Result<()> {
+        let mut last_frame = Instant::now();
+        let mut probed = false;
+        let mut reported = Instant::now();
+        loop {
+            // Send everything queued since the last read
+            loop {
+                match self.requests.try_recv() {
+                    Ok(Request::Close) | Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+                    Ok(request) => self.send(codec, request)?,
+                    Err(mpsc::TryRecvError::Empty) => break,
+                }
+            }
+
+            let read = match codec.try_read_message() {
+                // Betfair would send the same frame again on a new connection
+                Err(e) => match e.downcast::<ParseError>() {
+                    Ok(ParseError { frame, source }) => {
+                        last_frame = Instant::now();
+                        let _ = self.events.send(StreamEvent::Unparsed {
+                            frame,
+                            reason: source.to_string(),
+                        });
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                },
+                Ok(read) => read,
+            };
+            match read {
+                Some(message) => {
+                    last_frame = Instant::now();
+                    probed = false;
+                    // Every frame counts, also the segments of a larger message
+                    let received = chrono::Utc::now().timestamp_millis();
+                    self.telemetry
+                        .lock()
+                        .unwrap()
+                        .record_message(&message, received);
+                    if reported.elapsed() >= LATENCY_INTERVAL {
+                        reported = Instant::now();
+                        let snapshot = self.telemetry.lock().unwrap().snapshot();
+                        let _ = self.events.send(StreamEvent::Latency {
+                            connection: self.connection,
+                            latency: snapshot,
+                        });
+                    }
+                    // Clocks only move once a whole segmented message is in
+                    let Some(message) = self.segments.push(message) else {
+                        continue;
+                    };
+                    self.track(&message);
+                    self.answer(&message);
+                    let checked = match &message {
+                        ResponseMessage::Status(status) => self.check(status),
+                        _ => Ok(()),
+                    };
+                    if self
+                        .events
+                        .send(StreamEvent::Message {
+                            connection: self.connection,
+                            message,
+                        })
+                        .is_err()
+                    {
+                        // Nobody is listening anymore
+                        return Ok(());
+                    }
+                    checked?;
+                }
+                None => {
+                    let silent_for = last_frame.elapsed();
+                    let heartbeat = self.heartbeat();
+                    if silent_for >= heartbeat * self.config.stale_heartbeats {
+                        let _ = self.events.send(StreamEvent::Stale { silent_for });
+                        return Err(eyre::eyre!("no data for {:.1}s", silent_for.as_secs_f32()));
+                    }
+                    // Ask for a sign of life, the status answer resets the clock
+                    if silent_for >= heartbeat && !probed {
+                        codec.send_message(HeartbeatMessage::new())?;
+                        probed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // Shortest heartbeat among the subscriptions
+    fn heartbeat(&self) -> Duration {
+        let markets = self.markets.as_ref().and_then(|s| s.heartbeat);
+        let orders = self.orders.as_ref().and_then(|s| s.heartbeat);
+        markets
+            .into_iter()
+            .chain(orders)
+            .min()
+            .unwrap_or(DEFAULT_HEARTBEAT)
+    }
+
+    fn send(&mut self, codec: &mut LinesCodec, request: Request) -> eyre::Result<()> {
+        match request {
+            Request::Markets(subscription, reply, resume) => {
+                let markets = self.replace_markets(*subscription, None, resume);
+                let id = codec.send_message(markets.resumed())?;
+                markets.id = Some(id);
+                self.waiting.insert(id, reply);
+            }
+            Request::Orders(subscription, reply) => {
+                let id = codec.send_message(subscription.clone())?;
+                self.waiting.insert(id, reply);
+                let heartbeat_ms = subscription.heartbeat_ms;
+                let mut orders = Subscription::resuming(subscription, heartbeat_ms, None);
+                orders.id = Some(id);
+                self.orders = Some(orders);
+            }
+            Request::Races(reply) => {
+                let id = codec.send_message(RaceSubscriptionMessage::new())?;
+                self.waiting.insert(id, reply);
+                self.races = Some(Subscription::new(
+                    RaceSubscriptionMessage::new(),
+                    None,
+                    None,
+                ));
+            }
+            Request::Heartbeat(reply) => {
+                let id = codec.send_message(HeartbeatMessage::new())?;
+                self.waiting.insert(id, reply);
+            }
+            Request::Close => {}
+        }
+        Ok(())
+    }
+
+    // Clocks carry over when resuming, so a reconnect before the new image resumes too
+    fn replace_markets(
+        &mut self,
+        subscription: MarketSubscriptionMessage,
+        reply: Option<Reply>,
+        resume: bool,
+    ) -> &mut Subscription<MarketSubscriptionMessage> {
+        let heartbeat_ms = subscription.heartbeat_ms;
+        let mut markets = Subscription::resuming(subscription, heartbeat_ms, reply);
+        if let Some(current) = self.markets.take().filter(|_| resume) {
+            markets.initial_clk = current.initial_clk;
+            markets.clk = current.clk;
+        }
+        self.markets.insert(markets)
+    }
+
+    fn track(&mut self, message: &ResponseMessage) {
+        match message {
+            ResponseMessage::Mcm(mcm) => {
+                if let Some(markets) = self.markets.as_mut() {
+                    markets.update(mcm.id, &mcm.initial_clk, &mcm.clk, mcm.heartbeat_ms);
+                }
+            }
+            ResponseMessage::Ocm(ocm) => {
+                if let Some(orders) = self.orders.as_mut() {
+                    orders.update(ocm.id, &ocm.initial_clk, &ocm.clk, ocm.heartbeat_ms);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Hand a status to whoever waits for it
+    fn answer(&mut self, message: &ResponseMessage) {
+        if let ResponseMessage::Status(status) = message {
+            if let Some(reply) = status.id.and_then(|id| self.waiting.remove(&id)) {
+                let _ = reply.send(status.clone());
+            }
+        }
+    }
+
+    // Act on a status, Err when betfair has closed the connection
+    fn check(&mut self, status: &StatusResponse) -> eyre::Result<()> {
+        if let Some(available) = status.connections_available {
+            if available <= self.config.low_connections {
+                let _ = self.events.send(StreamEvent::ConnectionsLow { available });
+            }
+        }
+        if status.status_code == StatusCode::Success {
+            return Ok(());
+        }
+        if status.error_code.is_some_and(ErrorCode::is_session_error) {
+            self.session_expired = true;
+        }
+        if status.connection_closed {
+            return Err(StatusError(status.clone()).into());
+        }
+        Ok(())
+    }
+
+    // Keep trying with a growing delay, None if we were told to stop while waiting
+    fn reconnect(&mut self, error: eyre::Report) -> Option<LinesCodec> {
+        let mut retry_in = backoff(&error, INITIAL_BACKOFF);
+        let mut reason = error.to_string();
+        let mut attempt = 1;
+        loop {
+            let _ = self.events.send(StreamEvent::Disconnected {
+                reason,
+                attempt,
+                retry_in,
+            });
+            if !self.wait(retry_in) {
+                return None;
+            }
+            match self.open() {
+                Ok(codec) => return Some(codec),
+                Err(e) => {
+                    retry_in = backoff(&e, (retry_in * 2).min(MAX_BACKOFF));
+                    reason = e.to_string();
+                }
+            }
+            attempt += 1;
+        }
+    }
+
+    // Sleep while still taking requests, subscriptions made now are sent on reconnect
+    fn wait(&mut self, duration: Duration) -> bool {
+        let until = Instant::now() + duration;
+        loop {
+            let left = until.saturating_duration_since(Instant::now());
+            match self.requests.recv_timeout(left) {
+                Ok(Request::Markets(subscription, reply, resume)) => {
+                    self.replace_markets(*subscription, Some(reply), resume);
+                }
+                Ok(Request::Orders(subscription, reply)) => {
+                    let heartbeat_ms = subscription.heartbeat_ms;
+                    self.orders = Some(Subscription::resuming(
+                        subscription,
+                        heartbeat_ms,
+                        Some(reply),
+                    ))
+                }
+                Ok(Request::Races(reply)) => {
+                    self.races = Some(Subscription::new(
+                        RaceSubscriptionMessage::new(),
+                        None,
+                        Some(reply),
+                    ))
+                }
+                // Nothing to check without a connection, dropping the reply tells the caller
+                Ok(Request::Heartbeat(_)) => {}
+                Ok(Request::Close) | Err(RecvTimeoutError::Disconnected) => return false,
+                Err(RecvTimeoutError::Timeout) => return true,
+            }
+        }
+    }
+
+    // Connect, authenticate and resume whatever we were subscribed to
+    fn open(&mut self) -> eyre::Result<LinesCodec> {
+        self.segments.clear();
+        // Ids start over on a new connection
+        self.waiting.clear();
+        if self.session_expired {
+            if let Some(refresh) = self.config.refresh_session.as_ref() {
+                self.session_token = refresh().wrap_err("unable to refresh the session")?;
+            }
+            self.session_expired = false;
+        }
+        let mut codec = LinesCodec::connect(&self.config.endpoint)?;
+        // A server that takes the connection and never answers fails it
+        codec.set_read_timeout(Some(self.config.handshake_timeout))?;
+        let connection = codec.read_message()?;
+        let status = codec.authenticate(AuthenticationMessage::new(
+            &self.app_key,
+            &self.session_token,
+        ))?;
+        self.check(&status)?;
+        if status.status_code == StatusCode::Failure {
+            return Err(StatusError(status).into());
+        }
+        codec.set_read_timeout(Some(POLL_INTERVAL))?;
+        let _ = self.events.send(StreamEvent::Message {
+            connection: self.connection,
+            message: connection,
+        });
+
+        if let Some(markets) = self.markets.as_mut() {
+            let id = codec.send_message(markets.resumed())?;
+            markets.id = Some(id);
+            if let Some(reply) = markets.reply.take() {
+                self.waiting.insert(id, reply);
+            }
+        }
+        if let Some(orders) = self.orders.as_mut() {
+            let id = codec.send_message(orders.resumed())?;
+            orders.id = Some(id);
+            if let Some(reply) = orders.reply.take() {
+                self.waiting.insert(id, reply);
+            }
+        }
+        if let Some(races) = self.races.as_mut() {
+            let id = codec.send_message(races.message.clone())?;
+            if let Some(reply) = races.reply.take() {
+                self.waiting.insert(id, reply);
+            }
+        }
+        Ok(codec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::{
+        fake_server::{FakeServer, Reply},
+        model::ChangeType,
+        MarketDataFilter, MarketFilter, RaceCache,
+    };
+    use serde_json::Value;
+    use std::sync::Mutex;
+
+    fn config(server: &FakeServer) -> SessionConfig {
+        SessionConfig {
+            endpoint: server.endpoint(),
+            ..Default::default()
+        }
+    }
+
+    fn next_message(events: &Receiver<StreamEvent>) -> StreamEvent {
+        events.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn reconnect_resumes_subscriptions_from_last_clock() {
+        let server = FakeServer::start(|connection, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            match (connection, request["op"].as_str().unwrap()) {
+                (_, "authentication") => Reply::send([FakeServer::status_ok(id)]),
+                (0, "marketSubscription") => Reply::close([
+                    FakeServer::status_ok(id),
+                    format!(
+                        r#"{{"op":"mcm","id":{},"initialClk":"initial","clk":"first","pt":1,"ct":"SUB_IMAGE","mc":[]}}"#,
+                        id
+                    ),
+                    format!(
+                        r#"{{"op":"mcm","id":{},"clk":"second","pt":2,"mc":[]}}"#,
+                        id
+                    ),
+                ]),
+                (_, "marketSubscription") => Reply::send([
+                    FakeServer::status_ok(id),
+                    format!(
+                        r#"{{"op":"mcm","id":{},"clk":"third","pt":3,"ct":"RESUB_DELTA","mc":[]}}"#,
+                        id
+                    ),
+                ]),
+                _ => Reply::send([]),
+            }
+        });
+
+        let (tx, events) = mpsc::channel();
+        let session = StreamSession::connect_with(config(&server), "key", "token", tx).unwrap();
+        session
+            .subscribe_markets(MarketSubscriptionMessage::new(
+                MarketFilter::new().market_ids(["1.23"]),
+                MarketDataFilter::new(),
+            ))
+            .unwrap();
+
+        let mut disconnected = false;
+        let resumed = loop {
+            match next_message(&events) {
+                StreamEvent::Disconnected { attempt, .. } => {
+                    assert_eq!(attempt, 1);
+                    disconnected = true;
+                }
+                StreamEvent::Message {
+                    message: ResponseMessage::Mcm(mcm),
+                    ..
+                } if mcm.ct == Some(ChangeType::ResubDelta) => break mcm,
+                StreamEvent::Closed(reason) => panic!("session closed: {}", reason),
+                _ => {}
+            }
+        };
+        assert!(disconnected);
+        assert_eq!(resumed.clk.as_deref(), Some("third"));
+
+        let subscriptions: Vec<Value> = server
+            .requests()
+            .into_iter()
+            .filter(|(_, request)| request["op"] == "marketSubscription")
+            .map(|(_, request)| request)
+            .collect();
+        assert_eq!(subscriptions.len(), 2);
+        assert!(subscriptions[0].get("clk").is_none());
+        assert_eq!(subscriptions[1]["initialClk"], "initial");
+        assert_eq!(subscriptions[1]["clk"], "second");
+        assert_eq!(subscriptions[1]["marketFilter"]["marketIds"][0], "1.23");
+    }
+
+    #[test]
+    fn frames_of_a_replaced_subscription_leave_the_clocks() {
+        let first = Arc::new(Mutex::new(None));
+        let first_id = first.clone();
+        let server = FakeServer::start(move |connection, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            let mut first = first_id.lock().unwrap();
+            match (connection, request["op"].as_str().unwrap(), *first) {
+                (0, "marketSubscription", None) => {
+                    *first = Some(id);
+                    Reply::send([
+                        FakeServer::status_ok(id),
+                        format!(
+                            r#"{{"op":"mcm","id":{},"initialClk":"initial","clk":"first","pt":1,"ct":"SUB_IMAGE","mc":[]}}"#,
+                            id
+                        ),
+                    ])
+                }
+                // Still in flight from the first subscription when the new one lands
+                (0, "marketSubscription", Some(first)) => Reply::close([
+                    format!(
+                        r#"{{"op":"mcm","id":{},"clk":"stale","pt":2,"mc":[]}}"#,
+                        first
+                    ),
+                    FakeServer::status_ok(id),
+                ]),
+                _ => Reply::send([FakeServer::status_ok(id)]),
+            }
+        });
+        let (tx, events) = mpsc::channel();
+        let session = StreamSession::connect_with(config(&server), "key", "token", tx).unwrap();
+        let subscription = || {
+            MarketSubscriptionMessage::new(
+                MarketFilter::new().market_ids(["1.23"]),
+                MarketDataFilter::new(),
+            )
+        };
+        session.subscribe_markets(subscription()).unwrap();
+        while !matches!(
+            next_message(&events),
+            StreamEvent::Message {
+                message: ResponseMessage::Mcm(_),
+                ..
+            }
+        ) {}
+        session.resume_markets(subscription()).unwrap();
+
+        // The connection drops after the stale frame, the next one resumes
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let resubscribed = loop {
+            let resubscribed = server.requests().into_iter().find(|(connection, request)| {
+                *connection == 1 && request["op"] == "marketSubscription"
+            });
+            if let Some((_, request)) = resubscribed {
+                break request;
+            }
+            assert!(Instant::now() < deadline, "never resubscribed");
+            thread::sleep(POLL_INTERVAL);
+        };
+        assert_eq!(resubscribed["initialClk"], "initial");
+        assert_eq!(resubscribed["clk"], "first");
+    }
+
+    #[test]
+    fn unreadable_frame_is_skipped() {
+        let server = FakeServer::start(|_, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            match request["op"].as_str().unwrap() {
+                "marketSubscription" => Reply::send([
+                    FakeServer::status_ok(id),
+                    String::from(r#"{"op":"unknown","id":1}"#),
+                    format!(r#"{{"op":"mcm","id":{},"clk":"c","pt":1,"mc":[]}}"#, id),
+                ]),
+                _ => Reply::send([FakeServer::status_ok(id)]),
+            }
+        });
+        let (tx, events) = mpsc::channel();
+        let session = StreamSession::connect_with(config(&server), "key", "token", tx).unwrap();
+        session
+            .subscribe_markets(MarketSubscriptionMessage::new(
+                MarketFilter::new(),
+                MarketDataFilter::new(),
+            ))
+            .unwrap();
+
+        let mut unparsed = Vec::new();
+        loop {
+            match next_message(&events) {
+                StreamEvent::Unparsed { frame, .. } => unparsed.push(frame),
+                StreamEvent::Message {
+                    message: ResponseMessage::Mcm(_),
+                    ..
+                } => break,
+                StreamEvent::Disconnected { reason, .. } => panic!("reconnected: {}", reason),
+                _ => {}
+            }
+        }
+        assert_eq!(unparsed, [r#"{"op":"unknown","id":1}"#]);
+    }
+
+    #[test]
+    fn silent_server_fails_connect() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Take the connection and never say a word
+        thread::spawn(move || listener.incoming().collect::<Vec<_>>());
+        let config = SessionConfig {
+            endpoint: StreamEndpoint::plain("127.0.0.1", port),
+            handshake_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (tx, _events) = mpsc::channel();
+        let started = Instant::now();
+        assert!(StreamSession::connect_with(config, "key", "token", tx).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn authentication_failure_fails_connect() {
+        let server = FakeServer::start(|_, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            Reply::send([format!(
+                r#"{{"op":"status","id":{},"statusCode":"FAILURE","errorCode":"INVALID_APP_KEY","connectionClosed":true}}"#,
+                id
+            )])
+        });
+        let (tx, _events) = mpsc::channel();
+        let result = StreamSession::connect_with(config(&server), "bad", "token", tx);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn silent_connection_is_probed_then_replaced() {
+        // Betfair that answers the subscription and then goes quiet
+        let server = FakeServer::start(|_, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            match request["op"].as_str().unwrap() {
+                "heartbeat" => Reply::send([]),
+                _ => Reply::send([FakeServer::status_ok(id)]),
+            }
+        });
+        let (tx, events) = mpsc::channel();
+        let session = StreamSession::connect_with(
+            SessionConfig {
+                stale_heartbeats: 2,
+                ..config(&server)
+            },
+            "key",
+            "token",
+            tx,
+        )
+        .unwrap();
+        session
+            .subscribe_markets(
+                MarketSubscriptionMessage::new(MarketFilter::new(), MarketDataFilter::new())
+                    .heartbeat_ms(500),
+            )
+            .unwrap();
+
+        let silent_for = loop {
+            match next_message(&events) {
+                StreamEvent::Stale { silent_for } => break silent_for,
+                StreamEvent::Disconnected { reason, .. } => panic!("dropped: {}", reason),
+                _ => {}
+            }
+        };
+        assert!(silent_for >= Duration::from_secs(1));
+        assert!(matches!(
+            next_message(&events),
+            StreamEvent::Disconnected { attempt: 1, .. }
+        ));
+        assert!(server
+            .requests()
+            .iter()
+            .any(|(connection, request)| *connection == 0 && request["op"] == "heartbeat"));
+    }
+
+    #[test]
+    fn status_is_matched_to_its_request() {
+        // Answer out of order, with a status for some other request in between
+        let server = FakeServer::start(|_, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            match request["op"].as_str().unwrap() {
+                "marketSubscription" => Reply::send([
+                    format!(r#"{{"op":"mcm","id":{},"clk":"c","pt":1,"mc":[]}}"#, id),
+                    FakeServer::status_ok(id + 100),
+                    format!(
+                        r#"{{"op":"status","id":{},"statusCode":"FAILURE","errorCode":"SUBSCRIPTION_LIMIT_EXCEEDED","connectionClosed":false}}"#,
+                        id
+                    ),
+                ]),
+                _ => Reply::send([FakeServer::status_ok(id)]),
+            }
+        });
+        let (tx, events) = mpsc::channel();
+        let session = StreamSession::connect_with(config(&server), "key", "token", tx).unwrap();
+
+        let status = session
+            .subscribe_markets(MarketSubscriptionMessage::new(
+                MarketFilter::new(),
+                MarketDataFilter::new(),
+            ))
+            .unwrap()
+            .wait(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(status.id, Some(2));
+        assert_eq!(status.status_code, StatusCode::Failure);
+        assert_eq!(
+            status.error_code,
+            Some(ErrorCode::SubscriptionLimitExceeded)
+        );
+
+        let status = session
+            .heartbeat()
+            .unwrap()
+            .wait(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(status.id, Some(3));
+        assert_eq!(status.status_code, StatusCode::Success);
+
+        // The change message still reaches the subscribers
+        let mcm = loop {
+            if let StreamEvent::Message {
+                message: ResponseMessage::Mcm(mcm),
+                ..
+            } = next_message(&events)
+            {
+                break mcm;
+            }
+        };
+        assert_eq!(mcm.clk.as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn rejected_session_is_refreshed() {
+        // Betfair that expires the first token mid stream
+        let server = FakeServer::start(|_, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            match request["op"].as_str().unwrap() {
+                "authentication" if request["session"] == "stale" => Reply::close([format!(
+                    r#"{{"op":"status","id":{},"statusCode":"FAILURE","errorCode":"NO_SESSION","connectionClosed":true}}"#,
+                    id
+                )]),
+                "marketSubscription" if request["clk"].is_null() => Reply::close([
+                    r#"{"op":"status","statusCode":"FAILURE","errorCode":"INVALID_SESSION_INFORMATION","connectionClosed":true}"#.to_string(),
+                ]),
+                _ => Reply::send([FakeServer::status_ok(id)]),
+            }
+        });
+        let logins = Mutex::new(0);
+        let refresh: RefreshSession = Arc::new(move || {
+            let mut logins = logins.lock().unwrap();
+            *logins += 1;
+            Ok(format!("token-{}", logins))
+        });
+        let (tx, events) = mpsc::channel();
+        let session = StreamSession::connect_with(
+            SessionConfig {
+                refresh_session: Some(refresh),
+                ..config(&server)
+            },
+            "key",
+            "stale",
+            tx,
+        )
+        .unwrap();
+        session
+            .subscribe_markets(MarketSubscriptionMessage::new(
+                MarketFilter::new(),
+                MarketDataFilter::new(),
+            ))
+            .unwrap();
+
+        loop {
+            match next_message(&events) {
+                StreamEvent::Message {
+                    message: ResponseMessage::Connection(connection),
+                    ..
+                } if connection.connection_id == "fake-2" => break,
+                StreamEvent::Closed(reason) => panic!("session closed: {}", reason),
+                _ => {}
+            }
+        }
+        let sessions: Vec<Value> = server
+            .requests()
+            .into_iter()
+            .filter(|(_, request)| request["op"] == "authentication")
+            .map(|(_, request)| request["session"].clone())
+            .collect();
+        assert_eq!(sessions[..3], ["stale", "token-1", "token-2"]);
+    }
+
+    #[test]
+    fn throttled_connection_backs_off() {
+        let server = FakeServer::start(|_, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            match request["op"].as_str().unwrap() {
+                "authentication" => Reply::send([format!(
+                    r#"{{"op":"status","id":{},"statusCode":"SUCCESS","connectionClosed":false,"connectionsAvailable":1}}"#,
+                    id
+                )]),
+                _ => Reply::close([format!(
+                    r#"{{"op":"status","id":{},"statusCode":"FAILURE","errorCode":"TOO_MANY_REQUESTS","connectionClosed":true}}"#,
+                    id
+                )]),
+            }
+        });
+        let (tx, events) = mpsc::channel();
+        let session = StreamSession::connect_with(config(&server), "key", "token", tx).unwrap();
+        assert_eq!(
+            next_message(&events),
+            StreamEvent::ConnectionsLow { available: 1 }
+        );
+        session.heartbeat().unwrap();
+
+        let retry_in = loop {
+            if let StreamEvent::Disconnected { retry_in, .. } = next_message(&events) {
+                break retry_in;
+            }
+        };
+        assert_eq!(retry_in, THROTTLED_BACKOFF);
+    }
+
+    #[test]
+    fn changed_market_subscription_resumes() {
+        let server = FakeServer::start(|_, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            match request["op"].as_str().unwrap() {
+                "marketSubscription" => Reply::send([
+                    FakeServer::status_ok(id),
+                    format!(
+                        r#"{{"op":"mcm","id":{},"initialClk":"initial","clk":"clk-{}","pt":1,"mc":[]}}"#,
+                        id, id
+                    ),
+                ]),
+                _ => Reply::send([FakeServer::status_ok(id)]),
+            }
+        });
+        let (tx, events) = mpsc::channel();
+        let session = StreamSession::connect_with(config(&server), "key", "token", tx).unwrap();
+        let subscription = |market_id| {
+            MarketSubscriptionMessage::new(
+                MarketFilter::new().market_ids([market_id]),
+                MarketDataFilter::new(),
+            )
+        };
+        session.subscribe_markets(subscription("1.1")).unwrap();
+        while !matches!(
+            next_message(&events),
+            StreamEvent::Message {
+                message: ResponseMessage::Mcm(_),
+                ..
+            }
+        ) {}
+        session
+            .resume_markets(subscription("1.2"))
+            .unwrap()
+            .wait(Duration::from_secs(5))
+            .unwrap();
+
+        let subscriptions: Vec<Value> = server
+            .requests()
+            .into_iter()
+            .filter(|(_, request)| request["op"] == "marketSubscription")
+            .map(|(_, request)| request)
+            .collect();
+        assert!(subscriptions[0].get("clk").is_none());
+        assert_eq!(subscriptions[1]["marketFilter"]["marketIds"][0], "1.2");
+        assert_eq!(subscriptions[1]["initialClk"], "initial");
+        assert_eq!(subscriptions[1]["clk"], "clk-2");
+    }
+
+    #[test]
+    fn latency_of_every_frame() {
+        let server = FakeServer::start(|_, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            // Published a while ago, the second frame conflated
+            let pt = chrono::Utc::now().timestamp_millis() - 200;
+            match request["op"].as_str().unwrap() {
+                "marketSubscription" => Reply::send([
+                    format!(
+                        r#"{{"op":"mcm","id":{},"pt":{},"segmentType":"SEG_START","mc":[{{"id":"1.1"}}]}}"#,
+                        id, pt
+                    ),
+                    format!(
+                        r#"{{"op":"mcm","id":{},"clk":"c","pt":{},"segmentType":"SEG_END","mc":[{{"id":"1.1","con":true}}]}}"#,
+                        id, pt
+                    ),
+                    FakeServer::status_ok(id),
+                ]),
+                _ => Reply::send([FakeServer::status_ok(id)]),
+            }
+        });
+        let (tx, _events) = mpsc::channel();
+        let session = StreamSession::connect_with(config(&server), "key", "token", tx).unwrap();
+        assert_eq!(session.latency(), LatencySnapshot::default());
+
+        session
+            .subscribe_markets(MarketSubscriptionMessage::new(
+                MarketFilter::new(),
+                MarketDataFilter::new(),
+            ))
+            .unwrap()
+            .wait(Duration::from_secs(5))
+            .unwrap();
+        let latency = session.latency();
+        assert_eq!((latency.frames, latency.conflated), (2, 1));
+        assert!(latency.clock_skew_ms >= 200);
+        assert!(latency.p50_ms <= latency.p99_ms && latency.p99_ms <= latency.max_ms);
+    }
+
+    #[test]
+    fn race_subscription_is_sent_again_on_reconnect() {
+        let server = FakeServer::start(|connection, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            let rcm = format!(
+                r#"{{"op":"rcm","id":{},"pt":1,"rc":[{{"id":"r.1","mid":"1.1","rrc":[{{"ft":1,"id":7,"prg":{}}}]}}]}}"#,
+                id,
+                1000 - connection * 100
+            );
+            match (connection, request["op"].as_str().unwrap()) {
+                (0, "raceSubscription") => Reply::close([FakeServer::status_ok(id), rcm]),
+                (_, "raceSubscription") => Reply::send([FakeServer::status_ok(id), rcm]),
+                _ => Reply::send([FakeServer::status_ok(id)]),
+            }
+        });
+        let (tx, events) = mpsc::channel();
+        let session = StreamSession::connect_with(config(&server), "key", "token", tx).unwrap();
+        let status = session
+            .subscribe_races()
+            .unwrap()
+            .wait(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::Success);
+
+        let mut cache = RaceCache::new();
+        while cache
+            .race("1.1")
+            .is_none_or(|race| race.runners[&7].prg != Some(900.))
+        {
+            match next_message(&events) {
+                StreamEvent::Message {
+                    message: ResponseMessage::Rcm(rcm),
+                    ..
+                } => cache.update(&rcm),
+                StreamEvent::Closed(reason) => panic!("session closed: {}", reason),
+                _ => {}
+            }
+        }
+        let subscriptions = server
+            .requests()
+            .into_iter()
+            .filter(|(_, request)| request["op"] == "raceSubscription")
+            .count();
+        assert_eq!(subscriptions, 2);
+    }
+
+    #[test]
+    fn warm_start_clocks_survive_a_reconnect() {
+        let server = FakeServer::start(|connection, request: &Value| {
+            let id = request["id"].as_u64().unwrap();
+            match (connection, request["op"].as_str().unwrap()) {
+                // A resumed stream moves the clk on, the initial clk isn't sent again
+                (0, "orderSubscription") => Reply::close([
+                    FakeServer::status_ok(id),
+                    format!(
+                        r#"{{"op":"ocm","id":{},"clk":"newer","pt":1,"ct":"RESUB_DELTA"}}"#,
+                        id
+                    ),
+                ]),
+                _ => Reply::send([FakeServer::status_ok(id)]),
+            }
+        });
+        let (tx, events) = mpsc::channel();
+        let session = StreamSession::connect_with(config(&server), "key", "token", tx).unwrap();
+        session
+            .subscribe_orders(OrderSubscriptionMessage::new().resume("saved-initial", "saved"))
+            .unwrap();
+
+        let mut connections = 0;
+        while connections < 2 {
+            match next_message(&events) {
+                StreamEvent::Message {
+                    message: ResponseMessage::Connection(_),
+                    ..
+                } => connections += 1,
+                StreamEvent::Closed(reason) => panic!("session closed: {}", reason),
+                _ => {}
+            }
+        }
+        let subscriptions: Vec<Value> = loop {
+            let subscriptions: Vec<Value> = server
+                .requests()
+                .into_iter()
+                .filter(|(_, request)| request["op"] == "orderSubscription")
+                .map(|(_, request)| request)
+                .collect();
+            if subscriptions.len() == 2 {
+                break subscriptions;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        assert_eq!(subscriptions[1]["initialClk"], "saved-initial");
+        assert_eq!(subscriptions[1]["clk"], "newer");
+    }
+}
@@ -0,0 +1,166 @@
+// The caches saved to disk, so a new process starts from where the last one
+// stopped and only asks betfair for what changed since
+
+use color_eyre::eyre::{self, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use super::{model::MarketStatus, MarketCache, OrderCache};
+use crate::get_data_dir;
+
+const FILE_NAME: &str = "cache";
+
+/// How a snapshot is written, picked from the file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    /// Readable, for attaching to bug reports
+    Json,
+    /// MessagePack, smaller and faster to load
+    Binary,
+}
+
+impl SnapshotFormat {
+    fn of(path: &Path) -> eyre::Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(Self::Json),
+            Some("msgpack") => Ok(Self::Binary),
+            _ => Err(eyre::eyre!(
+                "unknown snapshot format {}, use .json or .msgpack",
+                path.display()
+            )),
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Binary => "msgpack",
+        }
+    }
+}
+
+/// Both caches with the clocks to resume their subscriptions from
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CacheSnapshot {
+    pub markets: MarketCache,
+    pub orders: OrderCache,
+}
+
+impl CacheSnapshot {
+    /// Where snapshots are kept, in the data dir
+    pub fn default_path(format: SnapshotFormat) -> eyre::Result<PathBuf> {
+        Ok(get_data_dir()?.join(format!("{}.{}", FILE_NAME, format.extension())))
+    }
+
+    /// Forget markets that have closed, nothing more will come for them
+    pub fn drop_closed(&mut self) {
+        self.markets.retain(|market| {
+            market
+                .definition
+                .as_ref()
+                .is_none_or(|definition| definition.status != MarketStatus::Closed)
+        });
+        self.orders.retain(|market| !market.closed);
+    }
+
+    /// Write the snapshot, the file is replaced in one go so a crash
+    /// midway leaves the previous one
+    pub fn save(&self, path: &Path) -> eyre::Result<()> {
+        let bytes = match SnapshotFormat::of(path)? {
+            SnapshotFormat::Json => serde_json::to_vec(self)?,
+            SnapshotFormat::Binary => rmp_serde::to_vec_named(self)?,
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, bytes)
+            .wrap_err_with(|| format!("unable to write {}", partial.display()))?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let bytes =
+            std::fs::read(path).wrap_err_with(|| format!("unable to read {}", path.display()))?;
+        let snapshot = match SnapshotFormat::of(path)? {
+            SnapshotFormat::Json => serde_json::from_slice(&bytes)?,
+            SnapshotFormat::Binary => rmp_serde::from_slice(&bytes)?,
+        };
+        Ok(snapshot)
+    }
+
+    /// The saved snapshot, or empty caches when there is none. A snapshot
+    /// that can't be read is still an error.
+    pub fn load_or_default(path: &Path) -> eyre::Result<Self> {
+        match std::fs::metadata(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            _ => Self::load(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARKET: &str = r#"{"initialClk":"i","clk":"c1","pt":1,"mc":[{"id":"1.1","img":true,"marketDefinition":{"status":"OPEN","bettingType":"ODDS","marketType":"WIN","eventTypeId":"7","eventId":"1","version":1,"inPlay":false,"betDelay":0,"bspMarket":false,"turnInPlayEnabled":true,"persistenceEnabled":true,"crossMatching":true,"runnersVoidable":false,"complete":true,"bspReconciled":false,"numberOfWinners":1,"numberOfActiveRunners":1,"marketTime":"2024-07-15T19:00:00.000Z","runners":[{"id":7,"status":"ACTIVE","sortPriority":1}]},"rc":[{"id":7,"hc":0.5,"atb":[[1.95,12.5]],"batl":[[0,1.96,40]],"ltp":1.95}]}]}"#;
+
+    #[test]
+    fn save_and_restore() {
+        let mut snapshot = CacheSnapshot::default();
+        snapshot
+            .markets
+            .update(1, &serde_json::from_str(MARKET).unwrap());
+        // The same market on a second connection, with clocks of its own
+        snapshot.markets.update(
+            2,
+            &serde_json::from_str(&MARKET.replace(r#""clk":"c1""#, r#""clk":"c2""#)).unwrap(),
+        );
+        snapshot.orders.update(&serde_json::from_str(
+            r#"{"initialClk":"oi","clk":"oc","pt":1,"oc":[{"id":"1.1","orc":[{"id":7,"uo":[{"id":"b1","p":2.5,"s":10,"side":"B","status":"E","pt":"L","ot":"L","pd":1,"sr":10}],"mb":[[2.5,4]]}]}]}"#,
+        ).unwrap());
+
+        let dir = std::env::temp_dir().join(format!("bfg-snapshot-{}", std::process::id()));
+        for format in [SnapshotFormat::Json, SnapshotFormat::Binary] {
+            let path = dir.join(format!("cache.{}", format.extension()));
+            snapshot.save(&path).unwrap();
+            let restored = CacheSnapshot::load(&path).unwrap();
+            assert_eq!(restored, snapshot);
+            assert_eq!(restored.markets.clocks(), [("i", "c1"), ("i", "c2")]);
+            assert_eq!(restored.orders.clocks(), Some(("oi", "oc")));
+        }
+
+        assert_eq!(
+            CacheSnapshot::load_or_default(&dir.join("missing.json")).unwrap(),
+            CacheSnapshot::default()
+        );
+        assert!(snapshot.save(&dir.join("cache.txt")).is_err());
+
+        // Closing the market takes it out of both caches
+        let mut closed = snapshot.clone();
+        closed.markets.update(
+            1,
+            &serde_json::from_str(&MARKET.replace(r#""status":"OPEN""#, r#""status":"CLOSED""#))
+                .unwrap(),
+        );
+        closed.orders.update(
+            &serde_json::from_str(r#"{"pt":2,"oc":[{"id":"1.1","closed":true}]}"#).unwrap(),
+        );
+        closed.drop_closed();
+        assert_eq!(closed.markets.markets().count(), 0);
+        assert_eq!(closed.orders.markets().count(), 0);
+        snapshot.drop_closed();
+        assert_eq!(snapshot.markets.markets().count(), 1);
+        assert_eq!(snapshot.orders.markets().count(), 1);
+
+        // Truncated or from another version, the caller decides to start cold
+        for format in [SnapshotFormat::Json, SnapshotFormat::Binary] {
+            let path = dir.join(format!("cache.{}", format.extension()));
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+            assert!(CacheSnapshot::load_or_default(&path).is_err());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,615 @@
+use color_eyre::eyre;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread,
+};
+
+use super::{MarketFilter, MarketSubscriptionMessage, StreamEvent, StreamSession};
+
+/// Betfair rejects a market subscription with more markets than this
+pub const MAX_MARKETS_PER_CONNECTION: usize = 200;
+
+/// Why a market is wanted, a market stays subscribed while any reason is left
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Interest {
+    /// Shown in an open ladder
+    Ladder,
+    Watchlist,
+    /// We have orders in it
+    Orders,
+}
+
+/// A connection the manager can put a market subscription on
+pub trait MarketSubscriber {
+    /// Tells the connection apart from the others, like `StreamSession::connection`
+    fn connection(&self) -> usize;
+
+    /// `resume` when this replaces an earlier subscription on the same connection
+    fn subscribe_markets(
+        &self,
+        subscription: MarketSubscriptionMessage,
+        resume: bool,
+    ) -> eyre::Result<()>;
+}
+
+impl MarketSubscriber for StreamSession {
+    fn connection(&self) -> usize {
+        StreamSession::connection(self)
+    }
+
+    fn subscribe_markets(
+        &self,
+        subscription: MarketSubscriptionMessage,
+        resume: bool,
+    ) -> eyre::Result<()> {
+        if resume {
+            self.resume_markets(subscription)?;
+        } else {
+            StreamSession::subscribe_markets(self, subscription)?;
+        }
+        Ok(())
+    }
+}
+
+// One connection and the markets it carries
+struct Connection<S> {
+    subscriber: S,
+    markets: BTreeSet<String>,
+    subscribed: bool,
+    // The markets changed since the last subscription that went through
+    dirty: bool,
+}
+
+/// Keeps track of the markets we are interested in and why, and spreads them
+/// over as few connections as the market limit allows. Betfair replaces the
+/// whole market subscription on every request, so when the set changes the
+/// connections that are affected resubscribe with their full market list,
+/// resuming from their clocks. Connections are opened on a thread of their
+/// own, connecting blocks on the handshake and `sync` runs on the tui thread.
+pub struct SubscriptionManager<S> {
+    template: MarketSubscriptionMessage,
+    interests: BTreeMap<String, BTreeSet<Interest>>,
+    connections: Vec<Connection<S>>,
+    max_connections: usize,
+    // Clocks of an earlier run, one for each new connection to pick up from
+    resume: VecDeque<(String, String)>,
+    connect: Arc<dyn Fn() -> eyre::Result<S> + Send + Sync>,
+    // The connection being opened, one at a time
+    opening: Option<Receiver<eyre::Result<S>>>,
+    // Told when the connection being opened is done
+    events: Option<Sender<StreamEvent>>,
+}
+
+impl<S: MarketSubscriber + Send + 'static> SubscriptionManager<S> {
+    /// Every connection subscribes like `template` with its own markets in the
+    /// filter, `connect` opens another connection when the existing ones are full.
+    pub fn new(
+        template: MarketSubscriptionMessage,
+        connect: impl Fn() -> eyre::Result<S> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            template,
+            interests: BTreeMap::new(),
+            connections: Vec::new(),
+            max_connections: usize::MAX,
+            resume: VecDeque::new(),
+            connect: Arc::new(connect),
+            opening: None,
+            events: None,
+        }
+    }
+
+    /// Send `StreamEvent::Opened` or `StreamEvent::OpenFailed` to `events` once
+    /// a connection opened in the background is done. Without it nothing says
+    /// when to `sync` again.
+    pub fn report_to(mut self, events: Sender<StreamEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Start the first subscription of each new connection from the clocks of
+    /// an earlier run, like those of a saved `MarketCache`, so betfair only
+    /// sends what changed since
+    pub fn resume_from<'a>(mut self, clocks: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        self.resume = clocks
+            .into_iter()
+            .map(|(initial_clk, clk)| (String::from(initial_clk), String::from(clk)))
+            .collect();
+        self
+    }
+
+    /// Never open more than `max` connections. Markets that don't fit are left
+    /// out and make `sync` fail once the others are subscribed.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    pub fn add(&mut self, interest: Interest, market_id: &str) {
+        self.interests
+            .entry(String::from(market_id))
+            .or_default()
+            .insert(interest);
+    }
+
+    pub fn remove(&mut self, interest: Interest, market_id: &str) {
+        if let Some(interests) = self.interests.get_mut(market_id) {
+            interests.remove(&interest);
+            if interests.is_empty() {
+                self.interests.remove(market_id);
+            }
+        }
+    }
+
+    /// Make `market_ids` the only markets wanted for `interest`
+    pub fn replace<'a>(
+        &mut self,
+        interest: Interest,
+        market_ids: impl IntoIterator<Item = &'a str>,
+    ) {
+        let wanted: BTreeSet<&str> = market_ids.into_iter().collect();
+        let dropped: Vec<String> = self
+            .interests
+            .iter()
+            .filter(|(id, interests)| {
+                interests.contains(&interest) && !wanted.contains(id.as_str())
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        for market_id in dropped {
+            self.remove(interest, &market_id);
+        }
+        for market_id in wanted {
+            self.add(interest, market_id);
+        }
+    }
+
+    /// Every market wanted for any reason
+    pub fn markets(&self) -> impl Iterator<Item = &str> {
+        self.interests.keys().map(String::as_str)
+    }
+
+    /// Tell the open connections apart, see `MarketSubscriber::connection`
+    pub fn connection_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.connections
+            .iter()
+            .map(|connection| connection.subscriber.connection())
+    }
+
+    /// A connection is being opened, the markets waiting for it go out on the
+    /// first `sync` after it is done
+    pub fn connecting(&self) -> bool {
+        self.opening.is_some()
+    }
+
+    /// The markets on each connection
+    pub fn connections(&self) -> Vec<Vec<&str>> {
+        self.connections
+            .iter()
+            .map(|connection| connection.markets.iter().map(String::as_str).collect())
+            .collect()
+    }
+
+    /// Bring the connections in line with the markets wanted. Markets stay on
+    /// the connection they are on, new ones fill the first connection with room
+    /// and connections left without markets are closed. When none has room a
+    /// connection is opened in the background and the markets wait for it. A
+    /// connection whose subscription or opening fails is tried again on the
+    /// next sync.
+    pub fn sync(&mut self) -> eyre::Result<()> {
+        let opened = self.take_opened();
+        for connection in self.connections.iter_mut() {
+            let before = connection.markets.len();
+            connection
+                .markets
+                .retain(|market_id| self.interests.contains_key(market_id));
+            connection.dirty |= connection.markets.len() != before;
+        }
+
+        let placed: BTreeSet<String> = self
+            .connections
+            .iter()
+            .flat_map(|connection| connection.markets.iter().cloned())
+            .collect();
+        let unplaced: Vec<String> = self
+            .interests
+            .keys()
+            .filter(|id| !placed.contains(*id))
+            .cloned()
+            .collect();
+        let mut overflow = 0;
+        for market_id in unplaced {
+            let index = match self
+                .connections
+                .iter()
+                .position(|connection| connection.markets.len() < MAX_MARKETS_PER_CONNECTION)
+            {
+                Some(index) => index,
+                None if self.opening.is_some() => continue,
+                None if self.connections.len() >= self.max_connections => {
+                    overflow += 1;
+                    continue;
+                }
+                None => {
+                    self.open()?;
+                    continue;
+                }
+            };
+            let connection = &mut self.connections[index];
+            connection.markets.insert(market_id);
+            connection.dirty = true;
+        }
+
+        for connection in self.connections.iter_mut() {
+            // An empty market filter would subscribe to everything, the connection is dropped instead
+            if !connection.dirty || connection.markets.is_empty() {
+                continue;
+            }
+            let mut subscription = self
+                .template
+                .clone()
+                .market_filter(MarketFilter::new().market_ids(connection.markets.iter()));
+            // Only the first subscription resumes, later ones are too far behind
+            let resume = self.resume.front().filter(|_| !connection.subscribed);
+            if let Some((initial_clk, clk)) = resume {
+                subscription = subscription.resume(initial_clk, clk);
+            }
+            connection
+                .subscriber
+                .subscribe_markets(subscription, connection.subscribed)?;
+            if !connection.subscribed {
+                self.resume.pop_front();
+            }
+            connection.subscribed = true;
+            connection.dirty = false;
+        }
+        self.connections
+            .retain(|connection| !connection.markets.is_empty());
+        opened?;
+        if overflow > 0 {
+            return Err(eyre::eyre!(
+                "{} of {} markets left out, no more than {} fit on {} connections",
+                overflow,
+                self.interests.len(),
+                self.max_connections * MAX_MARKETS_PER_CONNECTION,
+                self.max_connections
+            ));
+        }
+        Ok(())
+    }
+
+    // Start opening a connection, `take_opened` picks it up once it is done
+    fn open(&mut self) -> eyre::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let connect = self.connect.clone();
+        let events = self.events.clone();
+        thread::Builder::new()
+            .name(String::from("bfg-connect"))
+            .spawn(move || {
+                let opened = connect();
+                let event = match &opened {
+                    Ok(subscriber) => StreamEvent::Opened {
+                        connection: subscriber.connection(),
+                    },
+                    Err(e) => StreamEvent::OpenFailed(e.to_string()),
+                };
+                // The connection goes first so it is there when the event is handled
+                if tx.send(opened).is_ok() {
+                    if let Some(events) = events {
+                        let _ = events.send(event);
+                    }
+                }
+            })?;
+        self.opening = Some(rx);
+        Ok(())
+    }
+
+    // Add the connection being opened once it is done, the error if it failed
+    fn take_opened(&mut self) -> eyre::Result<()> {
+        let Some(opening) = &self.opening else {
+            return Ok(());
+        };
+        let opened = match opening.try_recv() {
+            Err(TryRecvError::Empty) => return Ok(()),
+            Err(TryRecvError::Disconnected) => Err(eyre::eyre!("connect thread has stopped")),
+            Ok(opened) => opened,
+        };
+        self.opening = None;
+        self.connections.push(Connection {
+            subscriber: opened?,
+            markets: BTreeSet::new(),
+            subscribed: false,
+            dirty: false,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::MarketDataFilter;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    // (connection, market ids, resume) for every subscription sent
+    type Sent = Arc<Mutex<Vec<(usize, Vec<String>, bool)>>>;
+
+    struct Recorder {
+        connection: usize,
+        sent: Sent,
+        // How many subscriptions to refuse before taking them
+        failures: Arc<AtomicUsize>,
+    }
+
+    impl MarketSubscriber for Recorder {
+        fn connection(&self) -> usize {
+            self.connection
+        }
+
+        fn subscribe_markets(
+            &self,
+            subscription: MarketSubscriptionMessage,
+            resume: bool,
+        ) -> eyre::Result<()> {
+            if self.failures.load(Ordering::Relaxed) > 0 {
+                self.failures.fetch_sub(1, Ordering::Relaxed);
+                return Err(eyre::eyre!("stream thread has stopped"));
+            }
+            let json = serde_json::to_value(&subscription)?;
+            let market_ids = serde_json::from_value(json["marketFilter"]["marketIds"].clone())?;
+            self.sent
+                .lock()
+                .unwrap()
+                .push((self.connection, market_ids, resume));
+            Ok(())
+        }
+    }
+
+    fn manager() -> (SubscriptionManager<Recorder>, Sent) {
+        let (manager, sent) = failing_manager(0);
+        (manager, sent)
+    }
+
+    // Every connection refuses the first `failures` subscriptions between them
+    fn failing_manager(failures: usize) -> (SubscriptionManager<Recorder>, Sent) {
+        let sent = Sent::default();
+        let recorded = sent.clone();
+        let failures = Arc::new(AtomicUsize::new(failures));
+        let opened = AtomicUsize::new(0);
+        let manager = SubscriptionManager::new(
+            MarketSubscriptionMessage::new(MarketFilter::new(), MarketDataFilter::new()),
+            move || {
+                Ok(Recorder {
+                    connection: opened.fetch_add(1, Ordering::Relaxed),
+                    sent: recorded.clone(),
+                    failures: failures.clone(),
+                })
+            },
+        );
+        (manager, sent)
+    }
+
+    // Sync until no connection is left opening, like the tui does on every
+    // `StreamEvent::Opened`
+    fn sync<S: MarketSubscriber + Send + 'static>(
+        sut: &mut SubscriptionManager<S>,
+    ) -> eyre::Result<()> {
+        sut.sync()?;
+        while sut.connecting() {
+            thread::sleep(Duration::from_millis(1));
+            sut.sync()?;
+        }
+        Ok(())
+    }
+
+    fn ids(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| format!("1.{:03}", i)).collect()
+    }
+
+    #[test]
+    fn merge_interests_and_resume_on_change() {
+        let (mut sut, sent) = manager();
+        sut.add(Interest::Ladder, "1.1");
+        sut.replace(Interest::Watchlist, ["1.1", "1.2"]);
+        sync(&mut sut).unwrap();
+        assert_eq!(
+            sent.lock().unwrap().as_slice(),
+            [(0, vec![String::from("1.1"), String::from("1.2")], false)]
+        );
+
+        // Still on the watchlist, nothing to do
+        sut.remove(Interest::Ladder, "1.1");
+        sync(&mut sut).unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 1);
+
+        sut.replace(Interest::Watchlist, ["1.2"]);
+        sut.add(Interest::Orders, "1.3");
+        sync(&mut sut).unwrap();
+        assert_eq!(
+            sent.lock().unwrap().last().unwrap(),
+            &(0, vec![String::from("1.2"), String::from("1.3")], true)
+        );
+        assert_eq!(sut.markets().collect::<Vec<_>>(), ["1.2", "1.3"]);
+    }
+
+    #[test]
+    fn spread_markets_over_connections() {
+        let (mut sut, sent) = manager();
+        for market_id in ids(0..450) {
+            sut.add(Interest::Watchlist, &market_id);
+        }
+        sync(&mut sut).unwrap();
+        let sizes: Vec<usize> = sut.connections().iter().map(Vec::len).collect();
+        assert_eq!(sizes, [200, 200, 50]);
+        assert_eq!(sent.lock().unwrap().len(), 3);
+
+        // Freed room on the first connection is used before the last one
+        for market_id in ids(0..10) {
+            sut.remove(Interest::Watchlist, &market_id);
+        }
+        for market_id in ids(450..465) {
+            sut.add(Interest::Watchlist, &market_id);
+        }
+        sync(&mut sut).unwrap();
+        let sizes: Vec<usize> = sut.connections().iter().map(Vec::len).collect();
+        assert_eq!(sizes, [200, 200, 55]);
+        let resent: Vec<(usize, bool)> = sent.lock().unwrap()[3..]
+            .iter()
+            .map(|(connection, _, resume)| (*connection, *resume))
+            .collect();
+        assert_eq!(resent, [(0, true), (2, true)]);
+    }
+
+    #[test]
+    fn close_empty_connections() {
+        let (mut sut, sent) = manager();
+        for market_id in ids(0..250) {
+            sut.add(Interest::Watchlist, &market_id);
+        }
+        sync(&mut sut).unwrap();
+        sut.replace(Interest::Watchlist, ids(0..100).iter().map(String::as_str));
+        sync(&mut sut).unwrap();
+        assert_eq!(sut.connections().len(), 1);
+        // The emptied connection was never sent an empty filter
+        assert!(sent
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(_, ids, _)| !ids.is_empty()));
+
+        // A new connection is opened when needed again
+        sut.replace(Interest::Watchlist, ids(0..201).iter().map(String::as_str));
+        sync(&mut sut).unwrap();
+        assert_eq!(sent.lock().unwrap().last().unwrap().0, 2);
+    }
+
+    #[test]
+    fn failed_subscription_is_sent_on_the_next_sync() {
+        let (mut sut, sent) = failing_manager(1);
+        sut.add(Interest::Ladder, "1.1");
+        assert!(sync(&mut sut).is_err());
+        assert!(sent.lock().unwrap().is_empty());
+
+        // Nothing changed since, the market still has to go out
+        sync(&mut sut).unwrap();
+        assert_eq!(
+            sent.lock().unwrap().as_slice(),
+            [(0, vec![String::from("1.1")], false)]
+        );
+        sync(&mut sut).unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn failed_connect_is_tried_again() {
+        let sent = Sent::default();
+        let recorded = sent.clone();
+        let attempts = AtomicUsize::new(0);
+        let (events, reported) = mpsc::channel();
+        let mut sut = SubscriptionManager::new(
+            MarketSubscriptionMessage::new(MarketFilter::new(), MarketDataFilter::new()),
+            move || {
+                if attempts.fetch_add(1, Ordering::Relaxed) == 0 {
+                    return Err(eyre::eyre!("connection refused"));
+                }
+                Ok(Recorder {
+                    connection: 0,
+                    sent: recorded.clone(),
+                    failures: Arc::default(),
+                })
+            },
+        )
+        .report_to(events);
+        sut.add(Interest::Orders, "1.1");
+        assert!(sync(&mut sut).is_err());
+        assert!(sut.connections().is_empty());
+        assert_eq!(
+            reported.recv_timeout(Duration::from_secs(5)).unwrap(),
+            StreamEvent::OpenFailed(String::from("connection refused"))
+        );
+
+        sync(&mut sut).unwrap();
+        assert_eq!(
+            sent.lock().unwrap().as_slice(),
+            [(0, vec![String::from("1.1")], false)]
+        );
+        assert_eq!(
+            reported.recv_timeout(Duration::from_secs(5)).unwrap(),
+            StreamEvent::Opened { connection: 0 }
+        );
+    }
+
+    #[test]
+    fn stay_within_max_connections() {
+        let (sut, sent) = manager();
+        let mut sut = sut.max_connections(1);
+        for market_id in ids(0..201) {
+            sut.add(Interest::Watchlist, &market_id);
+        }
+        let e = sync(&mut sut).unwrap_err();
+        assert!(
+            e.to_string().starts_with("1 of 201 markets left out"),
+            "{}",
+            e
+        );
+        // What fits still goes out
+        assert_eq!(sut.connections().len(), 1);
+        assert_eq!(sut.connections()[0].len(), 200);
+        assert_eq!(sent.lock().unwrap().len(), 1);
+
+        // Once there is room again the one left out goes out on the one connection
+        sut.remove(Interest::Watchlist, "1.000");
+        sync(&mut sut).unwrap();
+        assert_eq!(sut.connections()[0], ids(1..201));
+        assert_eq!(sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn each_new_connection_resumes_an_earlier_run() {
+        // The clocks of every subscription sent
+        struct Clocks(Arc<Mutex<Vec<Option<String>>>>);
+
+        impl MarketSubscriber for Clocks {
+            fn connection(&self) -> usize {
+                0
+            }
+
+            fn subscribe_markets(
+                &self,
+                subscription: MarketSubscriptionMessage,
+                _resume: bool,
+            ) -> eyre::Result<()> {
+                self.0.lock().unwrap().push(subscription.clk);
+                Ok(())
+            }
+        }
+
+        let clocks = Arc::new(Mutex::new(Vec::new()));
+        let sent = clocks.clone();
+        let mut sut = SubscriptionManager::new(
+            MarketSubscriptionMessage::new(MarketFilter::new(), MarketDataFilter::new()),
+            move || Ok(Clocks(sent.clone())),
+        )
+        .resume_from([("i", "c"), ("i2", "c2")]);
+        for market_id in ids(0..201) {
+            sut.add(Interest::Watchlist, &market_id);
+        }
+        sync(&mut sut).unwrap();
+        sut.add(Interest::Ladder, "1.201");
+        sync(&mut sut).unwrap();
+        assert_eq!(
+            clocks.lock().unwrap().as_slice(),
+            [Some(String::from("c")), Some(String::from("c2")), None]
+        );
+    }
+}
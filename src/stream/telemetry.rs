@@ -0,0 +1,148 @@
+// How far behind betfair the feed is running. Every change message carries
+// the time betfair published it, comparing that with when we read it gives the
+// latency, and the lowest latency seen says how far our clock is off.
+
+use std::{collections::VecDeque, fmt};
+
+use super::model::ResponseMessage;
+
+// Frames kept for the percentiles
+const WINDOW: usize = 1000;
+
+/// Latency over the last frames, in milliseconds from betfair publishing a
+/// frame to us reading it. Includes the clock skew, a negative latency means
+/// our clock is behind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// Frames seen since the session started
+    pub frames: u64,
+    /// Frames where betfair conflated market changes because we fell behind
+    pub conflated: u64,
+    pub p50_ms: i64,
+    pub p90_ms: i64,
+    pub p99_ms: i64,
+    pub max_ms: i64,
+    /// Lowest latency in the window. The network never takes less than
+    /// nothing so this is the skew plus the fastest transit, an upper bound
+    /// on how far our clock is ahead of betfair's.
+    pub clock_skew_ms: i64,
+}
+
+impl fmt::Display for LatencySnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "latency p50 {}ms p90 {}ms p99 {}ms, skew {}ms, {} conflated",
+            self.p50_ms, self.p90_ms, self.p99_ms, self.clock_skew_ms, self.conflated
+        )
+    }
+}
+
+/// Collects the latency of every frame read
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    latencies: VecDeque<i64>,
+    frames: u64,
+    conflated: u64,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record a frame betfair published at `pt` and we read at `received`,
+    /// both in epoch milliseconds
+    pub fn record(&mut self, pt: i64, received: i64, conflated: bool) {
+        if self.latencies.len() == WINDOW {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(received - pt);
+        self.frames += 1;
+        if conflated {
+            self.conflated += 1;
+        }
+    }
+
+    /// Record a frame if it carries a publish time
+    pub fn record_message(&mut self, message: &ResponseMessage, received: i64) {
+        match message {
+            ResponseMessage::Mcm(mcm) => {
+                let conflated = mcm
+                    .mc
+                    .iter()
+                    .flatten()
+                    .any(|change| change.con == Some(true));
+                self.record(mcm.pt, received, conflated);
+            }
+            ResponseMessage::Ocm(ocm) => self.record(ocm.pt, received, false),
+            ResponseMessage::Rcm(rcm) => self.record(rcm.pt, received, false),
+            _ => {}
+        }
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let mut sorted: Vec<i64> = self.latencies.iter().copied().collect();
+        sorted.sort_unstable();
+        let percentile = |p: usize| match sorted.len() {
+            0 => 0,
+            len => sorted[((len - 1) * p) / 100],
+        };
+        LatencySnapshot {
+            frames: self.frames,
+            conflated: self.conflated,
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: sorted.last().copied().unwrap_or_default(),
+            clock_skew_ms: sorted.first().copied().unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_over_the_window() {
+        let mut sut = Telemetry::new();
+        assert_eq!(sut.snapshot(), LatencySnapshot::default());
+
+        // Latencies 1..=100 with our clock 20ms ahead
+        for latency in 1..=100 {
+            sut.record(1_000, 1_000 + latency + 20, latency % 10 == 0);
+        }
+        let snapshot = sut.snapshot();
+        assert_eq!(snapshot.frames, 100);
+        assert_eq!(snapshot.conflated, 10);
+        assert_eq!(snapshot.p50_ms, 70);
+        assert_eq!(snapshot.p90_ms, 110);
+        assert_eq!(snapshot.p99_ms, 119);
+        assert_eq!(snapshot.max_ms, 120);
+        assert_eq!(snapshot.clock_skew_ms, 21);
+
+        // Old frames roll out of the window but stay counted
+        for _ in 0..WINDOW {
+            sut.record(1_000, 995, false);
+        }
+        let snapshot = sut.snapshot();
+        assert_eq!(snapshot.frames, 1100);
+        assert_eq!((snapshot.p99_ms, snapshot.clock_skew_ms), (-5, -5));
+    }
+
+    #[test]
+    fn conflated_market_changes() {
+        let mut sut = Telemetry::new();
+        let mcm = serde_json::from_str(
+            r#"{"op":"mcm","pt":100,"mc":[{"id":"1.1"},{"id":"1.2","con":true}]}"#,
+        )
+        .unwrap();
+        let ocm = serde_json::from_str(r#"{"op":"ocm","pt":100}"#).unwrap();
+        sut.record_message(&mcm, 130);
+        sut.record_message(&ocm, 150);
+        let snapshot = sut.snapshot();
+        assert_eq!((snapshot.frames, snapshot.conflated), (2, 1));
+        assert_eq!((snapshot.clock_skew_ms, snapshot.max_ms), (30, 50));
+    }
+}